@@ -0,0 +1,208 @@
+//! Compares the output path with the chained replaces it replaced, on
+//! colorful combat spam. Run with `cargo bench`.
+
+// The modules are shared with the server, which uses more of them than this does.
+#![allow(dead_code)]
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+#[path = "../src/ansi.rs"]
+mod ansi;
+
+#[path = "../src/mxp.rs"]
+mod mxp;
+
+#[path = "../src/policy.rs"]
+mod policy;
+
+#[path = "../src/render.rs"]
+mod render;
+
+#[path = "../src/rules.rs"]
+mod rules;
+
+mod config {
+    use crate::rules::Rule;
+
+    pub struct Config {
+        pub rules: Vec<Rule>
+    }
+
+    pub fn get_config() -> &'static Config {
+        static CONFIG: Config = Config { rules: Vec::new() };
+        &CONFIG
+    }
+}
+
+/// The output path as it was: `strip_telnet` from `conn.rs` and
+/// `ansi2html` from `ansi.rs`.
+mod chained {
+    use lazy_static::lazy_static;
+    use nanoserde::SerJson;
+    use regex::{Captures, Regex};
+
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    struct Styles {
+        background: Option<u8>,
+        blink: bool,
+        bold: bool,
+        color: Option<u8>,
+        inverse: bool,
+        italic: bool,
+        strikethrough: bool,
+        underline: bool
+    }
+
+    impl Styles {
+        fn new() -> Self {
+            Styles {
+                background: None,
+                blink: false,
+                bold: false,
+                color: None,
+                inverse: false,
+                italic: false,
+                strikethrough: false,
+                underline: false
+            }
+        }
+    }
+
+    fn ansi2html(str: &str) -> String {
+        lazy_static! {
+            static ref ANSI_COLORS: Regex = Regex::new("(?:\x1B\\[\\d+(?:;\\d+)*m)+").unwrap();
+            static ref CODE_REGEX: Regex = Regex::new(r"\d+").unwrap();
+        }
+        let mut styles = Styles::new();
+        let mut first = true;
+        let mut result = ANSI_COLORS.replace_all(str, |caps: &Captures| -> String {
+            let codes = CODE_REGEX
+                .find_iter(caps.get(0).unwrap().as_str())
+                .filter_map(|s| s.as_str().parse::<u8>().ok());
+
+            let mut newstyles = styles;
+            for code in codes {
+                match code {
+                    0 => newstyles = Styles::new(),
+                    1 => newstyles.bold = true,
+                    3 => newstyles.italic = true,
+                    4 | 21 => newstyles.underline = true,
+                    5 | 6 => newstyles.blink = true,
+                    7 => newstyles.inverse = true,
+                    9 => newstyles.strikethrough = true,
+                    2 | 22 => newstyles.bold = false,
+                    23 => newstyles.italic = false,
+                    24 => newstyles.underline = false,
+                    25 => newstyles.blink = false,
+                    27 => newstyles.inverse = false,
+                    29 => newstyles.strikethrough = false,
+                    30..=37 => newstyles.color = Some(code - 30),
+                    39 => newstyles.color = None,
+                    40..=47 => newstyles.background = Some(code - 40),
+                    49 => newstyles.background = None,
+                    _ => ()
+                }
+            }
+
+            if newstyles == styles {
+                return String::new();
+            }
+            styles = newstyles;
+
+            let mut html = String::with_capacity(32);
+            if !first {
+                html.push_str("</span>");
+            } else {
+                first = false;
+            }
+            html.push_str(r#"<span class=""#);
+            if let Some(background) = styles.background {
+                html.push_str(BACKGROUNDS[background as usize]);
+            }
+            if styles.blink {
+                html.push_str("tnc_blink ");
+            }
+            if styles.inverse {
+                html.push_str("tnc_inverse ");
+            }
+            if styles.strikethrough {
+                html.push_str("tnc_line_through ");
+            }
+            if styles.underline {
+                html.push_str("tnc_underline ");
+            }
+            if styles.bold {
+                html.push_str("tnc_bold ");
+            }
+            if let Some(color) = styles.color {
+                html.push_str(COLORS[color as usize]);
+            }
+            if styles.italic {
+                html.push_str("tnc_italic ");
+            }
+            if Some(&b' ') == html.as_bytes().last() {
+                html.pop();
+            }
+            html.push_str(r#"">"#);
+            html
+        }).to_string();
+        result.push_str("</span>");
+        result
+    }
+
+    static BACKGROUNDS: [&'static str; 8] = ["tnc_bg_black ", "tnc_bg_red ", "tnc_bg_green ", "tnc_bg_yellow ", "tnc_bg_blue ", "tnc_bg_magenta ", "tnc_bg_cyan ", "tnc_bg_silver "];
+    static COLORS: [&'static str; 8] = ["tnc_black ", "tnc_red ", "tnc_green ", "tnc_yellow ", "tnc_blue ", "tnc_magenta ", "tnc_cyan ", "tnc_white "];
+
+    fn strip_telnet(mut the_item: String) -> String {
+        the_item = the_item
+            .replace("&", "&amp;")
+            .replace("<", "&lt;")
+            .replace(">", "&gt;")
+            .replace("\t", "     ");
+
+        the_item = ansi2html(&the_item);
+
+        the_item
+            .replace("\x1B", "")
+            .replace("\r\n", "<br>")
+            .replace("\n\r", "<br>")
+            .replace("\r", "<br>")
+            .replace("\n", "<br>")
+            .replace("\u{00FF}\u{00F9}", "<br>")
+            .replace(char::is_control, "")
+            .replace("_-SYSTEM: CHAT-_", "")
+            .replace("`", "'")
+    }
+
+    pub fn render(text: &str) -> String {
+        strip_telnet(text.to_string()).serialize_json()
+    }
+}
+
+/// About 16KB of combat output, mostly short colored lines.
+fn combat_spam() -> String {
+    let lines = [
+        "\x1B[1;31mThe orc slashes you!\x1B[0m \x1B[33m[\x1B[1;33m42\x1B[0;33m]\x1B[0m\r\n",
+        "You \x1B[1;32mmassacre\x1B[0m the orc with your <sword>!\r\n",
+        "\x1B[36mThe orc's blood sprays across the floor & walls.\x1B[0m\r\n",
+        "\x1B[35mGossip\x1B[0m: Anyone seen the \"lost\" ring? Map at https://mud.example.org/map\r\n",
+        "HP: \x1B[32m120\x1B[0m/\x1B[32m150\x1B[0m  MP: \x1B[34m80\x1B[0m/\x1B[34m90\x1B[0m\tMV: 200\r\n"
+    ];
+    lines.iter().cycle().take(200).copied().collect()
+}
+
+fn output(c: &mut Criterion) {
+    let text = combat_spam();
+    let mut group = c.benchmark_group("output");
+    group.throughput(Throughput::Bytes(text.len() as u64));
+
+    group.bench_function("chained", |b| b.iter(|| chained::render(black_box(&text))));
+    for (name, format) in [("html", render::Format::Html), ("text", render::Format::Text), ("spans", render::Format::Spans)] {
+        let mut renderer = format.renderer(true, rules::RuleSet::for_host("mud.example.org"));
+        group.bench_function(name, |b| b.iter(|| renderer.render(black_box(&text), rules::Tail::Hold)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, output);
+criterion_main!(benches);
@@ -1,129 +1,437 @@
-use regex::{Regex, Captures};
-use lazy_static::lazy_static;
+use std::fmt;
 
+use crate::mxp::Mxp;
+use crate::rules::{self, HIGHLIGHT, HIGHLIGHT_END};
+
+/// A foreground or background color.
 #[derive(Clone, Copy, PartialEq, Eq)]
-struct Styles {
-    background: Option<u8>,
-    blink: bool,
-    bold: bool,
-    color: Option<u8>,
-    inverse: bool,
-    italic: bool,
-    strikethrough: bool,
-    underline: bool
+pub enum Color {
+    /// One of the xterm 256 colors. 0–15 are the basic and bright colors.
+    Indexed(u8),
+    Rgb(u8, u8, u8)
+}
+
+impl Color {
+    /// Reads the rest of an extended color, `5;n` or `2;r;g;b`, from `params`.
+    fn extended(params: &mut impl Iterator<Item = u32>) -> Option<Self> {
+        let mut next = || params.next().filter(|&value| value <= 255).map(|value| value as u8);
+        match next()? {
+            5 => Some(Color::Indexed(next()?)),
+            2 => Some(Color::Rgb(next()?, next()?, next()?)),
+            _ => None
+        }
+    }
+
+    /// Whether this is one of the 16 colors that are shown by name.
+    pub fn is_basic(&self) -> bool {
+        matches!(self, Color::Indexed(index) if *index < 16)
+    }
+}
+
+/// The 16 basic and bright colors by name, e.g. `red` or `bright_blue`,
+/// and anything else as `#rrggbb`.
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (r, g, b) = match *self {
+            Color::Indexed(index) if index < 16 => return f.write_str(NAMES[index as usize]),
+            Color::Indexed(index) if index >= 232 => {
+                let grey = 8 + (index - 232) * 10;
+                (grey, grey, grey)
+            },
+            Color::Indexed(index) => {
+                let index = index - 16;
+                let level = |value: u8| if value == 0 { 0 } else { 55 + value * 40 };
+                (level(index / 36), level(index / 6 % 6), level(index % 6))
+            },
+            Color::Rgb(r, g, b) => (r, g, b)
+        };
+        write!(f, "#{:02x}{:02x}{:02x}", r, g, b)
+    }
+}
+
+/// The SGR attributes in effect for a run of text, along with the class
+/// of any highlight rule that matched it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Styles {
+    pub background: Option<Color>,
+    pub blink: bool,
+    pub bold: bool,
+    pub color: Option<Color>,
+    pub highlight: Option<&'static str>,
+    pub inverse: bool,
+    pub italic: bool,
+    pub strikethrough: bool,
+    pub underline: bool
 }
 
 impl Styles {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Styles {
             background: None,
             blink: false,
             bold: false,
             color: None,
+            highlight: None,
             inverse: false,
             italic: false,
             strikethrough: false,
             underline: false
         }
     }
-}
-
-pub fn ansi2html(str: &str) -> String {
-    lazy_static! {
-        static ref ANSI_COLORS: Regex = Regex::new("(?:\x1B\\[\\d+(?:;\\d+)*m)+").unwrap();
-    }
-    let mut styles = Styles::new();
-    
-    let mut first = true;
-    let mut result = ANSI_COLORS.replace_all(str, |caps: &Captures| -> String {
-        lazy_static! {
-            static ref CODE_REGEX: Regex = Regex::new(r"\d+").unwrap();
-        }
-        let codes = CODE_REGEX
-            .find_iter(caps.get(0).unwrap().as_str())
-            .filter_map(|s| s.as_str().parse::<u8>().ok());
 
-        let mut newstyles = styles.clone();
-        for code in codes {
+    fn apply(&mut self, codes: &str) {
+        let parse = |value: &str| value.parse::<u32>().unwrap_or(0);
+        // An empty parameter list (`ESC[m`) means reset.
+        let mut params = codes.split(';');
+        while let Some(param) = params.next() {
+            let code = param.split(':').next().map(parse).unwrap_or(0);
+            let mut extended = || -> Option<Color> {
+                if param.contains(':') {
+                    // `38:5:n`, or `38:2:r:g:b` with an optional color space ID before `r`.
+                    let mut sub: Vec<u32> = param.split(':').skip(1).map(parse).collect();
+                    if sub.len() == 5 && sub[0] == 2 {
+                        sub.remove(1);
+                    }
+                    Color::extended(&mut sub.into_iter())
+                } else {
+                    Color::extended(&mut params.by_ref().map(parse))
+                }
+            };
             match code {
-                0 => newstyles = Styles::new(),
-                1 => newstyles.bold = true,
-                3 => newstyles.italic = true,
-                4 | 21 => newstyles.underline = true,
-                5 | 6 => newstyles.blink = true,
-                7 => newstyles.inverse = true,
-                9 => newstyles.strikethrough = true,
-                2 | 22 => newstyles.bold = false,
-                23 => newstyles.italic = false,
-                24 => newstyles.underline = false,
-                25 => newstyles.blink = false,
-                27 => newstyles.inverse = false,
-                29 => newstyles.strikethrough = false,
-                30..=37 => newstyles.color = Some(code - 30),
-                39 => newstyles.color = None,
-                40..=47 => newstyles.background = Some(code - 40),
-                49 => newstyles.background = None,
+                0 => *self = Styles { highlight: self.highlight, ..Styles::new() },
+                1 => self.bold = true,
+                3 => self.italic = true,
+                4 | 21 => self.underline = true,
+                5 | 6 => self.blink = true,
+                7 => self.inverse = true,
+                9 => self.strikethrough = true,
+                2 | 22 => self.bold = false,
+                23 => self.italic = false,
+                24 => self.underline = false,
+                25 => self.blink = false,
+                27 => self.inverse = false,
+                29 => self.strikethrough = false,
+                30..=37 => self.color = Some(Color::Indexed((code - 30) as u8)),
+                38 => self.color = extended().or(self.color),
+                39 => self.color = None,
+                40..=47 => self.background = Some(Color::Indexed((code - 40) as u8)),
+                48 => self.background = extended().or(self.background),
+                49 => self.background = None,
+                90..=97 => self.color = Some(Color::Indexed((code - 90 + 8) as u8)),
+                100..=107 => self.background = Some(Color::Indexed((code - 100 + 8) as u8)),
                 _ => ()
             }
         }
+    }
+}
 
-        if newstyles == styles {
-            return String::new();
-        }
+/// Longest control sequence held back while waiting for the rest of it.
+const MAX_PENDING: usize = 64;
 
-        styles = newstyles;
+/// Longest control string (OSC, DCS, etc.) held back while waiting for its terminator.
+const MAX_PENDING_STRING: usize = 4096;
 
-        let mut html = String::with_capacity(32);
-        if !first {
-            html.push_str("</span>");
-        } else {
-            first = false;
-        }
-        html.push_str(r#"<span class=""#);
-        
-        if let Some(background) = styles.background {
-            html.push_str(BACKGROUNDS[background as usize]);
-        }
+enum Sequence {
+    /// A complete escape sequence of the given length in bytes.
+    Complete(usize),
+    /// Possibly the start of a sequence, cut off by the end of the input.
+    Partial,
+    /// Not a valid sequence. Only the escape character is dropped.
+    Invalid
+}
 
-        if styles.blink {
-            html.push_str("tnc_blink ");
-        }
+/// Finds the end of the ECMA-48 escape sequence at the start of `bytes`,
+/// which begins with ESC.
+fn scan_sequence(bytes: &[u8]) -> Sequence {
+    let partial = |limit: usize| if bytes.len() < limit { Sequence::Partial } else { Sequence::Invalid };
+    match bytes.get(1) {
+        None => Sequence::Partial,
+        // CSI: parameter bytes, then intermediate bytes, then a final byte.
+        Some(b'[') => {
+            let mut i = 2;
+            while let Some(0x30..=0x3F) = bytes.get(i) {
+                i += 1;
+            }
+            while let Some(0x20..=0x2F) = bytes.get(i) {
+                i += 1;
+            }
+            match bytes.get(i) {
+                Some(0x40..=0x7E) => Sequence::Complete(i + 1),
+                Some(_) => Sequence::Invalid,
+                None => partial(MAX_PENDING)
+            }
+        },
+        // OSC, DCS, SOS, PM and APC: a string ended by ST (`ESC \`), or BEL as xterm allows.
+        Some(b']' | b'P' | b'X' | b'^' | b'_') => {
+            for (i, &byte) in bytes.iter().enumerate().skip(2) {
+                match byte {
+                    0x07 => return Sequence::Complete(i + 1),
+                    0x1B => return match bytes.get(i + 1) {
+                        Some(b'\\') => Sequence::Complete(i + 2),
+                        // Another sequence cuts the string short.
+                        Some(_) => Sequence::Complete(i),
+                        None => partial(MAX_PENDING_STRING)
+                    },
+                    _ => {}
+                }
+            }
+            partial(MAX_PENDING_STRING)
+        },
+        // nF: intermediate bytes, then a final byte, e.g. `ESC ( B`.
+        Some(0x20..=0x2F) => {
+            let mut i = 2;
+            while let Some(0x20..=0x2F) = bytes.get(i) {
+                i += 1;
+            }
+            match bytes.get(i) {
+                Some(0x30..=0x7E) => Sequence::Complete(i + 1),
+                Some(_) => Sequence::Invalid,
+                None => partial(MAX_PENDING)
+            }
+        },
+        // Fp, Fe and Fs: a single byte, e.g. `ESC c` or `ESC 7`.
+        Some(0x30..=0x7E) => Sequence::Complete(2),
+        Some(_) => Sequence::Invalid
+    }
+}
 
-        if styles.inverse {
-            html.push_str("tnc_inverse ");
-        }
+/// Whether `uri` may be shown as a link: only http and https URLs are
+/// allowed, without anything that could break out of an attribute.
+pub fn is_safe_link(uri: &str) -> bool {
+    let lower = uri.to_ascii_lowercase();
+    let rest = match lower.strip_prefix("https://").or(lower.strip_prefix("http://")) {
+        Some(rest) => rest,
+        None => return false
+    };
+    !rest.is_empty() && !uri.chars().any(|c| c.is_whitespace() || c.is_control() || matches!(c, '"' | '\'' | '<' | '>' | '`'))
+}
 
-        if styles.strikethrough {
-            html.push_str("tnc_line_through ");
-        }
+/// Length of the escape sequence at the start of `str`, or 1 for a lone
+/// escape character.
+pub fn escape_len(str: &str) -> usize {
+    match scan_sequence(str.as_bytes()) {
+        Sequence::Complete(len) => len,
+        Sequence::Partial | Sequence::Invalid => 1
+    }
+}
 
-        if styles.underline {
-            html.push_str("tnc_underline ");
-        }
+/// Length of `str` without the escape sequence cut off at its end, if any.
+pub fn finished_len(str: &str) -> usize {
+    str.match_indices('\x1B')
+        .find(|&(i, _)| matches!(scan_sequence(str[i..].as_bytes()), Sequence::Partial))
+        .map_or(str.len(), |(i, _)| i)
+}
+
+/// Where clicking a run of text leads.
+#[derive(Clone, PartialEq)]
+pub enum Link {
+    /// An http(s) URL, from OSC 8 or MXP `<a>`.
+    Url(String),
+    /// A command sent to the MUD, from MXP `<send>`.
+    Send(String)
+}
 
-        if styles.bold {
-            html.push_str("tnc_bold ");
+/// What `AnsiParser` found in the MUD's output.
+pub enum Token<'a> {
+    /// A run of text in one style, and the link it belongs to, if any.
+    Text(&'a Styles, Option<&'a Link>, &'a str),
+    /// The screen was cleared, by `ESC[2J`, `ESC[3J` or `ESC c`.
+    Clear
+}
+
+/// Splits text into runs that share the same SGR attributes, and picks out
+/// the few other sequences the web client can act on. Anything else is
+/// dropped. One parser is kept per connection: the current style, and any
+/// escape sequence cut off at the end of a chunk, carry over to the next
+/// call, so the output looks the same however the MUD's output is split into reads.
+pub struct AnsiParser {
+    styles: Styles,
+    /// The OSC 8 hyperlink the text is in, if any.
+    link: Option<Link>,
+    pending: String,
+    /// Without color, SGR sequences are still consumed but every run is unstyled.
+    color: bool,
+    /// Set once the MUD has agreed to speak MXP.
+    mxp: Option<Mxp>
+}
+
+impl AnsiParser {
+    pub fn new(color: bool) -> Self {
+        Self { styles: Styles::new(), link: None, pending: String::new(), color, mxp: None }
+    }
+
+    /// Starts or stops looking for MXP tags in the text.
+    pub fn set_mxp(&mut self, enabled: bool) {
+        if !enabled {
+            self.mxp = None;
+        } else if self.mxp.is_none() {
+            self.mxp = Some(Mxp::new(self.color));
         }
+    }
 
-        if let Some(color) = styles.color {
-            html.push_str(COLORS[color as usize]);
+    /// Calls `token` for each non-empty run of text in `str` and each
+    /// sequence of interest, in order. Stray BEL characters are dropped.
+    pub fn parse(&mut self, str: &str, mut token: impl FnMut(Token)) {
+        // Only a sequence split across reads needs the input copied.
+        let joined;
+        let mut rest = if self.pending.is_empty() {
+            str
+        } else {
+            joined = std::mem::take(&mut self.pending) + str;
+            joined.as_str()
+        };
+
+        let unstyled = Styles::new();
+        loop {
+            let (text, sequence) = match memchr::memchr3(b'\x1B', b'\x07', HIGHLIGHT as u8, rest.as_bytes()) {
+                Some(i) => (&rest[..i], Some(&rest[i..])),
+                None => (rest, None)
+            };
+            if !text.is_empty() {
+                let styles = if self.color { &self.styles } else { &unstyled };
+                match &mut self.mxp {
+                    Some(mxp) => mxp.text(text, styles, self.link.as_ref(), &mut token),
+                    None => token(Token::Text(styles, self.link.as_ref(), text))
+                }
+            }
+            let sequence = match sequence {
+                Some(sequence) => sequence,
+                None => break
+            };
+            if sequence.starts_with('\x07') {
+                rest = &sequence[1..];
+                continue;
+            }
+            if let Some(marker) = sequence.strip_prefix(HIGHLIGHT) {
+                let (index, after) = marker.split_once(HIGHLIGHT_END).unwrap_or((marker, ""));
+                self.styles.highlight = index.parse().ok().and_then(rules::highlight_class);
+                rest = after;
+                continue;
+            }
+            match scan_sequence(sequence.as_bytes()) {
+                Sequence::Complete(len) => {
+                    self.control(&sequence[..len], &mut token);
+                    rest = &sequence[len..];
+                },
+                Sequence::Partial => {
+                    self.pending = sequence.to_string();
+                    break;
+                },
+                Sequence::Invalid => rest = &sequence[1..]
+            }
         }
+    }
 
-        if styles.italic {
-            html.push_str("tnc_italic ");
+    /// Acts on a complete escape sequence.
+    fn control(&mut self, sequence: &str, token: &mut impl FnMut(Token)) {
+        let bytes = sequence.as_bytes();
+        match bytes[1] {
+            b'[' => {
+                let (&last, params) = bytes[2..].split_last().unwrap_or((&0, &[]));
+                // Private sequences (`ESC[?25l`) and ones with intermediate bytes aren't ours.
+                if params.iter().any(|b| !matches!(b, b'0'..=b'9' | b';' | b':')) {
+                    return;
+                }
+                let params = &sequence[2..sequence.len() - 1];
+                match last {
+                    b'm' => self.styles.apply(params),
+                    b'J' if params == "2" || params == "3" => token(Token::Clear),
+                    // MXP line modes, e.g. `ESC[1z` for a secure line.
+                    b'z' => if let Some(mxp) = &mut self.mxp {
+                        mxp.set_mode(params.parse().unwrap_or(0), token);
+                    },
+                    _ => {}
+                }
+            },
+            b']' => {
+                let string = &sequence[2..];
+                let string = string.strip_suffix('\x07').or(string.strip_suffix("\x1B\\")).unwrap_or(string);
+                // OSC 8: `ESC]8;<params>;<uri>`. An empty URI ends the link.
+                if let Some(link) = string.strip_prefix("8;") {
+                    let uri = link.split_once(';').map(|(_, uri)| uri).unwrap_or("");
+                    self.link = Some(uri).filter(|uri| is_safe_link(uri)).map(|uri| Link::Url(uri.to_string()));
+                }
+            },
+            b'c' => {
+                self.styles = Styles::new();
+                self.link = None;
+                token(Token::Clear);
+            },
+            _ => {}
         }
+    }
+}
 
-        if Some(&b' ') == html.as_bytes().last() {
-            html.pop();
+static NAMES: [&'static str; 16] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    "bright_black", "bright_red", "bright_green", "bright_yellow", "bright_blue", "bright_magenta", "bright_cyan", "bright_white"
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Colors, 256-color and truecolor SGR, non-ASCII text, an OSC 8 link
+    /// ended by ST, BEL, a clear, a charset switch and a private sequence.
+    const INPUT: &str = concat!(
+        "plain \x1B[1;31mbold red\x1B[0m \x1B[38;5;208mor\x1B[38;2;1;2;3mange\x1B[m é 漢字 🙂\n",
+        "\x1B]8;;https://example.org/\x07link\x1B]8;;\x1B\\ after\x07\x1B[2Jcleared\x1B(B text\x1B[?25l ",
+        "\x1B[4;48;5;17munder\x1B[24m\x1B[49m\x1Bcend"
+    );
+
+    /// The tokens for `chunks` parsed one after another, with the style and
+    /// link written out whenever they change.
+    fn render(chunks: &[&str]) -> String {
+        let mut parser = AnsiParser::new(true);
+        let mut out = String::new();
+        let mut last = String::new();
+        for chunk in chunks {
+            parser.parse(chunk, |token| match token {
+                Token::Text(styles, link, text) => {
+                    let state = format!(
+                        "[{:?} {:?} {}{}{}{}{}{} {}]",
+                        styles.color.map(|color| color.to_string()),
+                        styles.background.map(|color| color.to_string()),
+                        styles.bold as u8, styles.italic as u8, styles.underline as u8,
+                        styles.strikethrough as u8, styles.blink as u8, styles.inverse as u8,
+                        match link {
+                            Some(Link::Url(url)) => url.as_str(),
+                            Some(Link::Send(command)) => command.as_str(),
+                            None => ""
+                        }
+                    );
+                    if state != last {
+                        out.push_str(&state);
+                        last = state;
+                    }
+                    out.push_str(text);
+                },
+                Token::Clear => {
+                    out.push_str("[clear]");
+                    last.clear();
+                }
+            });
         }
+        out
+    }
 
-        html.push_str(r#"">"#);
+    #[test]
+    fn renders_sequences() {
+        assert_eq!(render(&[INPUT]), concat!(
+            "[None None 000000 ]plain [Some(\"red\") None 100000 ]bold red[None None 000000 ] ",
+            "[Some(\"#ff8700\") None 000000 ]or[Some(\"#010203\") None 000000 ]ange[None None 000000 ] é 漢字 🙂\n",
+            "[None None 000000 https://example.org/]link[None None 000000 ] after[clear][None None 000000 ]cleared text ",
+            "[None Some(\"#00005f\") 001000 ]under[clear][None None 000000 ]end"
+        ));
+    }
 
-        html
-    }).to_string();
-    result.push_str("</span>");
-    result
+    #[test]
+    fn same_output_however_split() {
+        let whole = render(&[INPUT]);
+        for (at, _) in INPUT.char_indices().skip(1) {
+            assert_eq!(render(&[&INPUT[..at], &INPUT[at..]]), whole, "split at {}", at);
+        }
+        let chars: Vec<String> = INPUT.chars().map(String::from).collect();
+        assert_eq!(render(&chars.iter().map(String::as_str).collect::<Vec<_>>()), whole);
+    }
 }
-
-static BACKGROUNDS: [&'static str; 8] = ["tnc_bg_black ", "tnc_bg_red ", "tnc_bg_green ", "tnc_bg_yellow ", "tnc_bg_blue ", "tnc_bg_magenta ", "tnc_bg_cyan ", "tnc_bg_silver "];
-static COLORS: [&'static str; 8] = ["tnc_black ","tnc_red ","tnc_green ","tnc_yellow ","tnc_blue ","tnc_magenta ","tnc_cyan ","tnc_white "];
\ No newline at end of file
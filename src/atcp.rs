@@ -0,0 +1,58 @@
+use nanoserde::SerJson;
+
+/// Telnet option number for ATCP.
+pub const ATCP: u8 = 200;
+
+/// Sent to the MUD as soon as it agrees to speak ATCP, enabling the modules
+/// that `static/js/atcp.js` knows how to display.
+pub fn hello(client_name: &str) -> Vec<u8> {
+    format!("hello {} {}\nchar_name 1\nchar_vitals 1\nroom_brief 1\nroom_exits 1\nmap_display 1",
+        client_name, env!("CARGO_PKG_VERSION")).into_bytes()
+}
+
+/// Exit names as used by the sidebar, indexed by the long forms some servers send.
+static EXITS: [(&'static str, &'static str); 12] = [
+    ("north", "n"), ("northeast", "ne"), ("east", "e"), ("southeast", "se"),
+    ("south", "s"), ("southwest", "sw"), ("west", "w"), ("northwest", "nw"),
+    ("up", "u"), ("down", "d"), ("in", "in"), ("out", "out")
+];
+
+fn normalize_exit(exit: &str) -> String {
+    let exit = exit.trim().to_ascii_lowercase();
+    EXITS.iter()
+        .find(|(long, _)| *long == exit)
+        .map(|(_, short)| short.to_string())
+        .unwrap_or(exit)
+}
+
+/// Splits a `Package.Message <data>` payload into the package and its data
+/// as a JSON value, e.g. `Room.Exits n,s` becomes `Room.Exits` and `["n","s"]`
+/// and `Char.Name Foo` becomes `Char.Name` and `"Foo"`.
+pub fn decode(buffer: &[u8]) -> Option<(String, String)> {
+    let payload = String::from_utf8_lossy(buffer);
+    let payload = payload.trim();
+    let (package, data) = payload
+        .split_once(char::is_whitespace)
+        .unwrap_or((payload, ""));
+    if package.is_empty() || !package.chars().all(|c| c.is_ascii_alphanumeric() || c == '.') {
+        return None;
+    }
+    let value = if package == "Room.Exits" {
+        data.split(',')
+            .filter(|exit| !exit.trim().is_empty())
+            .map(normalize_exit)
+            .collect::<Vec<String>>()
+            .serialize_json()
+    } else {
+        data.trim().replace('\n', " ").serialize_json()
+    };
+    Some((package.to_string(), value))
+}
+
+/// Translates a payload into the JSON object that `handle_ATCP` expects,
+/// e.g. `{"ATCP_Room_Exits":["n","s"]}`.
+pub fn to_client_json(buffer: &[u8]) -> Option<String> {
+    let (package, value) = decode(buffer)?;
+    let field = format!("ATCP_{}", package.replace('.', "_"));
+    Some(format!("{{{}:{}}}", field.serialize_json(), value))
+}
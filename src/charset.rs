@@ -0,0 +1,242 @@
+use encoding_rs::{CoderResult, Encoding, EncoderResult, UTF_8};
+use lazy_static::lazy_static;
+
+/// Telnet option number for CHARSET (RFC 2066).
+pub const CHARSET: u8 = 42;
+
+const REQUEST: u8 = 1;
+const ACCEPTED: u8 = 2;
+const REJECTED: u8 = 3;
+
+/// The upper half of code page 437, which encoding_rs doesn't provide.
+static CP437_HIGH: &'static str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒ",
+    "áíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐",
+    "└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀",
+    "αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{A0}"
+);
+
+lazy_static! {
+    static ref CP437: Vec<char> = CP437_HIGH.chars().collect();
+}
+
+/// The character encoding used on the MUD connection.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Charset {
+    Utf8,
+    Cp437,
+    Other(&'static Encoding)
+}
+
+impl Charset {
+    /// Looks up a charset by any of its common names, e.g. `latin1`, `cp437` or `big5`.
+    pub fn from_label(label: &str) -> Option<Self> {
+        let label = label.trim();
+        match label.to_ascii_uppercase().as_str() {
+            "CP437" | "IBM437" | "IBM-437" | "437" | "CSPC8CODEPAGE437" => return Some(Charset::Cp437),
+            _ => {}
+        }
+        let encoding = Encoding::for_label(label.as_bytes())?;
+        if encoding == UTF_8 {
+            Some(Charset::Utf8)
+        } else if encoding.output_encoding() != encoding {
+            // UTF-16 and the "replacement" encoding can't be used both ways.
+            None
+        } else {
+            Some(Charset::Other(encoding))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Charset::Utf8 => "UTF-8",
+            Charset::Cp437 => "CP437",
+            Charset::Other(encoding) => encoding.name()
+        }
+    }
+
+    /// Encodes user input for the MUD. Characters the charset can't
+    /// represent are replaced with `?`.
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        match self {
+            Charset::Utf8 => text.as_bytes().to_vec(),
+            Charset::Cp437 => text.chars().map(|c| {
+                if c.is_ascii() {
+                    c as u8
+                } else {
+                    CP437.iter().position(|&h| h == c).map(|i| 0x80 + i as u8).unwrap_or(b'?')
+                }
+            }).collect(),
+            Charset::Other(encoding) => {
+                let mut encoder = encoding.new_encoder();
+                let mut out = Vec::with_capacity(text.len() + 16);
+                let mut src = text;
+                loop {
+                    let (result, read) = encoder.encode_from_utf8_to_vec_without_replacement(src, &mut out, true);
+                    src = &src[read..];
+                    match result {
+                        EncoderResult::InputEmpty => break,
+                        EncoderResult::OutputFull => out.reserve(src.len() * 4 + 16),
+                        EncoderResult::Unmappable(_) => out.push(b'?')
+                    }
+                }
+                out
+            }
+        }
+    }
+}
+
+/// Decodes the MUD's output one read at a time. A character split between
+/// reads is held back until the rest of it arrives.
+pub struct Decoder {
+    charset: Charset,
+    /// Unset for CP437, where every byte is a character of its own.
+    decoder: Option<encoding_rs::Decoder>
+}
+
+impl Decoder {
+    pub fn new(charset: Charset) -> Self {
+        let decoder = match charset {
+            Charset::Utf8 => Some(UTF_8.new_decoder_without_bom_handling()),
+            Charset::Cp437 => None,
+            Charset::Other(encoding) => Some(encoding.new_decoder_without_bom_handling())
+        };
+        Self { charset, decoder }
+    }
+
+    pub fn charset(&self) -> Charset {
+        self.charset
+    }
+
+    /// Switches to another charset, as negotiated with the MUD. Any character
+    /// left incomplete in the old one is dropped.
+    pub fn set_charset(&mut self, charset: Charset) {
+        if charset != self.charset {
+            *self = Self::new(charset);
+        }
+    }
+
+    pub fn decode(&mut self, bytes: &[u8]) -> String {
+        let decoder = match &mut self.decoder {
+            Some(decoder) => decoder,
+            None => return bytes.iter().map(|&b| {
+                if b < 0x80 { b as char } else { CP437[(b - 0x80) as usize] }
+            }).collect()
+        };
+        let mut out = String::with_capacity(decoder.max_utf8_buffer_length(bytes.len()).unwrap_or(bytes.len() * 3));
+        let mut src = bytes;
+        loop {
+            let (result, read, _) = decoder.decode_to_string(src, &mut out, false);
+            src = &src[read..];
+            match result {
+                CoderResult::InputEmpty => break,
+                CoderResult::OutputFull => out.reserve(src.len() * 3 + 16)
+            }
+        }
+        out
+    }
+}
+
+/// Picks a charset from a `REQUEST [TTABLE <version>] <sep>name<sep>name...`
+/// payload and returns the reply to send along with the charset to switch to.
+/// `preferred` is the per-connection override, if the user gave one.
+pub fn respond(buffer: &[u8], preferred: Option<Charset>) -> Option<(Vec<u8>, Option<Charset>)> {
+    let (&command, mut rest) = buffer.split_first()?;
+    match command {
+        REQUEST => {},
+        ACCEPTED | REJECTED => return None,
+        _ => return Some((vec![REJECTED], None))
+    }
+    if rest.starts_with(b"[TTABLE]") {
+        rest = rest.get(9..).unwrap_or(&[]);
+    }
+    let (&sep, names) = match rest.split_first() {
+        Some(split) => split,
+        None => return Some((vec![REJECTED], None))
+    };
+    let offered: Vec<(&[u8], Charset)> = names
+        .split(|&b| b == sep)
+        .filter_map(|name| Some((name, Charset::from_label(&String::from_utf8_lossy(name))?)))
+        .collect();
+    let wanted = preferred.unwrap_or(Charset::Utf8);
+    let chosen = offered.iter()
+        .find(|(_, charset)| *charset == wanted)
+        .or(if preferred.is_none() { offered.first() } else { None });
+    match chosen {
+        Some((name, charset)) => {
+            let mut reply = vec![ACCEPTED];
+            reply.extend_from_slice(name);
+            Some((reply, Some(*charset)))
+        },
+        None => Some((vec![REJECTED], None))
+    }
+}
+
+/// Our own `REQUEST`, sent when the MUD asks us to start negotiating.
+pub fn request(preferred: Option<Charset>) -> Vec<u8> {
+    let mut out = vec![REQUEST, b';'];
+    out.extend_from_slice(preferred.unwrap_or(Charset::Utf8).name().as_bytes());
+    out
+}
+
+/// The charset the MUD picked in reply to `request`, if it accepted.
+pub fn accepted(buffer: &[u8]) -> Option<Charset> {
+    let (&command, name) = buffer.split_first()?;
+    if command != ACCEPTED {
+        return None;
+    }
+    Charset::from_label(&String::from_utf8_lossy(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes `bytes` split into two reads at `at`.
+    fn decode_split(charset: Charset, bytes: &[u8], at: usize) -> String {
+        let mut decoder = Decoder::new(charset);
+        decoder.decode(&bytes[..at]) + &decoder.decode(&bytes[at..])
+    }
+
+    /// Encodes `text`, which `charset` must be able to represent, and checks
+    /// it decodes the same however the bytes are split.
+    fn check_splits(charset: Charset, text: &str) {
+        let bytes = charset.encode(text);
+        assert!(!bytes.contains(&b'?'), "{} can't encode the text", charset.name());
+        assert_eq!(Decoder::new(charset).decode(&bytes), text);
+        for at in 1..bytes.len() {
+            assert_eq!(decode_split(charset, &bytes, at), text, "{} split at {}", charset.name(), at);
+        }
+    }
+
+    #[test]
+    fn decodes_utf_8_split_anywhere() {
+        check_splits(Charset::Utf8, "Café, naïve. 漢字と한국어. 🙂🐉 Ω≈ç\r\n");
+    }
+
+    #[test]
+    fn decodes_big5_split_anywhere() {
+        check_splits(Charset::from_label("big5").unwrap(), "Hello, 你好世界. 中文字 ok\r\n");
+    }
+
+    #[test]
+    fn decodes_cp437_split_anywhere() {
+        check_splits(Charset::Cp437, "Café ½ ░▒▓ ╔═╗ ║ö║ αß ±≥\r\n");
+    }
+
+    #[test]
+    fn replaces_unencodable_input() {
+        assert_eq!(Charset::Cp437.encode("a 漢 é"), b"a ? \x82");
+        assert_eq!(Charset::from_label("big5").unwrap().encode("a 🙂 中"), b"a ? \xA4\xA4");
+    }
+
+    #[test]
+    fn answers_request() {
+        let latin1 = Charset::from_label("latin1").unwrap();
+        assert_eq!(respond(b"\x01;ISO-8859-1;UTF-8", None).unwrap().0, b"\x02UTF-8");
+        assert_eq!(respond(b"\x01 ISO-8859-1 CP437", None).unwrap().0, b"\x02ISO-8859-1");
+        assert!(respond(b"\x01;ISO-8859-1;UTF-8", Some(latin1)).unwrap().1 == Some(latin1));
+        assert_eq!(respond(b"\x01;UTF-8", Some(Charset::Cp437)).unwrap().0, b"\x03");
+        assert!(respond(b"\x02UTF-8", None).is_none());
+    }
+}
@@ -1,18 +1,32 @@
-use std::net::{ToSocketAddrs, IpAddr};
+use std::net::SocketAddr;
+use std::time::Duration;
 
 use anyhow::{Result, Context, bail};
+use nanoserde::{DeJson, SerJson};
 //use regex::Regex;
-use crate::ansi::ansi2html;
-use crate::config::get_config;
+use crate::config::{get_config, Config, Target};
 use crate::debug;
+use crate::atcp::{self, ATCP};
+use crate::charset::{self, Charset, Decoder, CHARSET};
+use crate::gmcp::{self, GmcpMessage, GMCP};
+#[cfg(feature = "mccp")]
+use crate::mccp::{self, MCCP3};
+use crate::msdp::{self, MSDP};
+use crate::mxp::MXP;
+use crate::render::{Format, Output, Renderer};
+use crate::rules::{RuleSet, Tail, MAX_HELD};
+use crate::ttype::{TerminalType, TTYPE};
 
 pub trait ConnParent {
     fn try_send(&self, msg: String) -> ();
     fn try_close_conn(&self) -> ();
 }
 
-fn try_json(parent: &impl ConnParent, msg: impl SerJson) {
-    parent.try_send(msg.serialize_json())
+/// Sends `msg` to the web client, unless its protocol has no way to say it.
+fn send(parent: &impl ConnParent, protocol: Protocol, msg: ServerMessage) {
+    if let Some(msg) = protocol.encode(msg) {
+        parent.try_send(msg);
+    }
 }
 
 /// The `conn` is constantly listening for new messages on its receiver.
@@ -21,52 +35,379 @@ pub fn start(mut parent: impl ConnParent + Send + 'static) -> UnboundedSender<St
     let (tx, rx) 
         = mpsc::unbounded_channel();
     tokio::spawn(async move {
-        if let Err(err) = Box::pin(handle_conn(&mut parent, rx)).await {
+        let mut protocol = Protocol::Legacy;
+        if let Err(err) = Box::pin(handle_conn(&mut parent, &mut protocol, rx)).await {
             debug!("Connection failed with: {}", err);
-            try_json(&parent, ClientMessage {
-                message: format!("<br>{}<br>", err)
-            });
-            parent.try_close_conn();
-        } else {}
+            send(&parent, protocol, ServerMessage::Error(&err));
+        }
+        parent.try_close_conn();
     });
     tx
 }
 
-pub async fn handle_conn(parent: &mut impl ConnParent, mut rx: UnboundedReceiver<String>) -> Result<()> {
-    let (host, port, tls) = get_details(&mut rx).await?;
-    try_json(parent, ClientMessage {
-        message: format!("<br>Attempting to establish a {}connection with {}:{}<br>", 
-            if tls { "TLS " } else { "" }, host, port)
-    });
-    Box::pin(telnet_handler(host, port, parent, rx, tls)).await?;
-    Ok(())
+/// Settles the protocol with the web client's first message, then connects
+/// wherever it asks to.
+pub async fn handle_conn(parent: &mut impl ConnParent, protocol: &mut Protocol, mut rx: UnboundedReceiver<String>) -> Result<()> {
+    let mut msg = rx.recv().await.context("Client disconnect")?;
+    if msg.trim_start().starts_with('{') {
+        match Protocol::Json(PROTOCOL_VERSION).decode(&msg)? {
+            ClientMessage::Hello(0) => bail!("Unsupported protocol version 0"),
+            ClientMessage::Hello(version) => *protocol = Protocol::Json(version.min(PROTOCOL_VERSION)),
+            _ => bail!("Expected a hello message")
+        }
+        send(parent, *protocol, ServerMessage::Hello);
+        msg = rx.recv().await.context("Client disconnect")?;
+    }
+    let details = match protocol.decode(&msg)? {
+        ClientMessage::Connect(details) => details,
+        _ => bail!("Command unimplemented")
+    };
+    send(parent, *protocol, ServerMessage::Connecting(&details));
+    let res = Box::pin(telnet_handler(details, *protocol, parent, rx)).await;
+    send(parent, *protocol, ServerMessage::Disconnected);
+    res
+}
+
+/// The newest version of the JSON protocol this server speaks.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How the web client talks to us. A client that opens with
+/// `{"type": "hello", "version": <n>}` gets the JSON protocol, in the lower
+/// of its version and ours. Anything else is taken for an older client.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Protocol {
+    /// `PHUD:<COMMAND> <args>` and lines of input in, untagged objects like
+    /// `{"message": ...}` out.
+    Legacy,
+    /// Objects tagged with their `type` both ways, in the negotiated version.
+    Json(u32)
+}
+
+/// A message from the web client.
+pub enum ClientMessage {
+    /// Asks for the JSON protocol, in at most this version.
+    Hello(u32),
+    Connect(ConnectDetails),
+    /// A line of input for the MUD.
+    Input(String),
+    /// Subnegotiation payloads, checked and ready to send.
+    Gmcp(Vec<u8>),
+    Msdp(Vec<u8>),
+    /// The output now fits this many columns and rows.
+    Naws(u16, u16),
+    /// Ends the connection to the MUD.
+    Disconnect
+}
+
+/// Every message of the JSON protocol, e.g. `{"type": "input", "text": "look"}`.
+/// Which of the other fields are used depends on the type.
+#[derive(DeJson)]
+struct ClientFrame {
+    #[nserde(rename = "type")]
+    kind: String,
+    version: Option<u32>,
+    host: Option<String>,
+    port: Option<u16>,
+    tls: Option<bool>,
+    charset: Option<String>,
+    color: Option<bool>,
+    renderer: Option<String>,
+    text: Option<String>,
+    package: Option<String>,
+    /// GMCP data, as a JSON string like the MUD receives it. It is kept a
+    /// string both ways so the proxy passes it on without parsing it.
+    data: Option<String>,
+    command: Option<String>,
+    args: Option<Vec<String>>,
+    cols: Option<u16>,
+    rows: Option<u16>
+}
+
+/// A message for the web client.
+pub enum ServerMessage<'a> {
+    /// Answers the client's hello with the version both sides will speak.
+    Hello,
+    /// Rendered MUD output, already JSON in the form of the connection's
+    /// renderer. Prompts are shown apart from the rest.
+    Output { prompt: bool, data: &'a str },
+    /// The MUD cleared the screen.
+    Clear,
+    Connecting(&'a ConnectDetails),
+    Connected,
+    Disconnected,
+    Error(&'a anyhow::Error),
+    /// The MUD turned one of `OPTION_NAMES` on or off.
+    TelnetOption(&'static str, bool),
+    Gmcp(gmcp::Gmcp),
+    /// Subnegotiation payloads, as the MUD sent them.
+    Msdp(&'a [u8]),
+    Atcp(&'a [u8])
+}
+
+impl Protocol {
+    /// Reads a message from the web client.
+    pub fn decode(self, msg: &str) -> Result<ClientMessage> {
+        match self {
+            Protocol::Legacy => decode_legacy(msg),
+            Protocol::Json(_) => decode_json(msg)
+        }
+    }
+
+    /// Formats `msg` for the web client, or returns `None` if this protocol
+    /// has no way to say it.
+    pub fn encode(self, msg: ServerMessage) -> Option<String> {
+        match self {
+            Protocol::Legacy => encode_legacy(msg),
+            Protocol::Json(version) => encode_json(version, msg)
+        }
+    }
+}
+
+fn decode_legacy(msg: &str) -> Result<ClientMessage> {
+    Ok(match parse_client_command(msg) {
+        Some(("CONNECT", args)) => ClientMessage::Connect(parse_connect(args, get_config().target.as_ref())?),
+        Some(("GMCP", args)) => ClientMessage::Gmcp(gmcp::encode(args).context("Malformed GMCP message")?),
+        Some(("MSDP", args)) => ClientMessage::Msdp(msdp::encode(args).context("Malformed MSDP command")?),
+        Some(("NAWS", args)) => {
+            let (cols, rows) = parse_window_size(args).context("Malformed window size")?;
+            ClientMessage::Naws(cols, rows)
+        },
+        Some((cmd, _)) => bail!("Unknown client command {}", cmd),
+        None => ClientMessage::Input(msg.trim().to_string())
+    })
+}
+
+fn decode_json(msg: &str) -> Result<ClientMessage> {
+    let frame = ClientFrame::deserialize_json(msg)
+        .map_err(|err| anyhow::anyhow!("Invalid message: {:?}", err))?;
+    Ok(match frame.kind.as_str() {
+        "hello" => ClientMessage::Hello(frame.version.context("No protocol version given")?),
+        "connect" => {
+            let mut details = ConnectDetails::new(get_config().target.as_ref(), frame.host.as_deref(), frame.port, frame.tls)?;
+            if let Some(charset) = &frame.charset {
+                details.set_option("charset", charset)?;
+            }
+            if let Some(renderer) = &frame.renderer {
+                details.set_option("renderer", renderer)?;
+            }
+            details.color = frame.color;
+            ClientMessage::Connect(details)
+        },
+        "input" => ClientMessage::Input(frame.text.context("No text given")?),
+        "gmcp" => ClientMessage::Gmcp(
+            gmcp::encode_message(&frame.package.unwrap_or_default(), &frame.data.unwrap_or_default())
+                .context("Malformed GMCP message")?
+        ),
+        "msdp" => ClientMessage::Msdp(
+            msdp::encode_command(&frame.command.unwrap_or_default(), &frame.args.unwrap_or_default())
+                .context("Malformed MSDP command")?
+        ),
+        "naws" => match (frame.cols, frame.rows) {
+            (Some(cols), Some(rows)) if cols > 0 && rows > 0 => ClientMessage::Naws(cols, rows),
+            _ => bail!("Malformed window size")
+        },
+        "disconnect" => ClientMessage::Disconnect,
+        kind => bail!("Unknown message type {}", kind)
+    })
+}
+
+/// The `error` field of error messages, one of the `ConnError::kind`s or `error`.
+fn error_kind(err: &anyhow::Error) -> &'static str {
+    err.downcast_ref::<ConnError>().map_or("error", ConnError::kind)
 }
 
-pub async fn get_details(rx: &mut UnboundedReceiver<String>) -> Result<(String, u16, bool)> {
-    let msg = rx.recv().await.context("Client disconnect")?;
-    let mut parser = msg.split(" ");
-    let cmd = parser.next().context("No command provided")?;
-    if cmd == "PHUD:CONNECT" {
-        let host = parser.next().context("Invalid host")?.to_string();
+fn encode_legacy(msg: ServerMessage) -> Option<String> {
+    Some(match msg {
+        ServerMessage::Hello => return None,
+        ServerMessage::Output { prompt, data } => format!("{{\"{}\":{}}}", if prompt { "prompt" } else { "message" }, data),
+        ServerMessage::Clear => CLEAR.to_string(),
+        ServerMessage::Connecting(details) => TextMessage {
+            message: format!("<br>Attempting to establish a {}connection with {}:{}<br>",
+                if details.tls { "TLS " } else { "" }, details.host, details.port)
+        }.serialize_json(),
+        ServerMessage::Connected => CONNECTED.to_string(),
+        ServerMessage::Disconnected => DISCONNECTED.to_string(),
+        ServerMessage::Error(err) => ErrorMessage {
+            error: error_kind(err).to_string(),
+            message: format!("<br>{}<br>", err)
+        }.serialize_json(),
+        ServerMessage::TelnetOption("echo", enabled) => EchoMessage {
+            local_echo: if enabled { "off" } else { "on" }.to_string()
+        }.serialize_json(),
+        ServerMessage::TelnetOption(..) => return None,
+        ServerMessage::Gmcp(gmcp) => GmcpMessage { gmcp }.serialize_json(),
+        ServerMessage::Msdp(buffer) => msdp::to_client_json(buffer),
+        ServerMessage::Atcp(buffer) => atcp::to_client_json(buffer)?
+    })
+}
+
+fn encode_json(version: u32, msg: ServerMessage) -> Option<String> {
+    Some(match msg {
+        ServerMessage::Hello => format!(r#"{{"type":"hello","version":{}}}"#, version),
+        ServerMessage::Output { prompt, data } => format!(r#"{{"type":"output","stream":"{}","data":{}}}"#,
+            if prompt { "prompt" } else { "main" }, data),
+        ServerMessage::Clear => r#"{"type":"clear"}"#.to_string(),
+        ServerMessage::Connecting(details) => format!(r#"{{"type":"status","status":"connecting","host":{},"port":{},"tls":{}}}"#,
+            details.host.serialize_json(), details.port, details.tls),
+        ServerMessage::Connected => r#"{"type":"status","status":"connected"}"#.to_string(),
+        ServerMessage::Disconnected => r#"{"type":"status","status":"disconnected"}"#.to_string(),
+        ServerMessage::Error(err) => format!(r#"{{"type":"error","error":"{}","message":{}}}"#,
+            error_kind(err), err.to_string().serialize_json()),
+        ServerMessage::TelnetOption(option, enabled) => format!(r#"{{"type":"option","option":"{}","enabled":{}}}"#, option, enabled),
+        ServerMessage::Gmcp(gmcp) => format!(r#"{{"type":"gmcp","package":{},"data":{}}}"#,
+            gmcp.package.serialize_json(), gmcp.data.serialize_json()),
+        ServerMessage::Msdp(buffer) => {
+            let mut out = String::from(r#"{"type":"msdp","data":"#);
+            msdp::parse(buffer).to_json(&mut out);
+            out.push('}');
+            out
+        },
+        ServerMessage::Atcp(buffer) => {
+            let (package, data) = atcp::decode(buffer)?;
+            format!(r#"{{"type":"atcp","package":{},"data":{}}}"#, package.serialize_json(), data)
+        }
+    })
+}
+
+/// Ways the connection to the MUD can fail, told apart for the web client
+/// by `ConnError::kind`.
+#[derive(Debug)]
+pub enum ConnError {
+    /// The host has no addresses.
+    Resolve(String),
+    /// Every address of the host is private or blocked by the policy.
+    Rejected(String),
+    /// No address could be connected to.
+    Connect(String),
+    ConnectTimeout(String),
+    Tls(String),
+    TlsTimeout(String),
+    /// The MUD sent nothing for `--idle-timeout` seconds.
+    IdleTimeout(u64),
+    /// The MUD closed the connection.
+    Closed
+}
+
+impl ConnError {
+    /// The `error` field sent to the web client.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ConnError::Resolve(_) => "resolve",
+            ConnError::Rejected(_) => "rejected",
+            ConnError::Connect(_) => "connect",
+            ConnError::ConnectTimeout(_) => "connect_timeout",
+            ConnError::Tls(_) => "tls",
+            ConnError::TlsTimeout(_) => "tls_timeout",
+            ConnError::IdleTimeout(_) => "idle_timeout",
+            ConnError::Closed => "closed"
+        }
+    }
+}
+
+impl std::fmt::Display for ConnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConnError::Resolve(host) => write!(f, "Unable to resolve {}", host),
+            ConnError::Rejected(reason) | ConnError::Connect(reason) => f.write_str(reason),
+            ConnError::ConnectTimeout(host) => write!(f, "Timed out connecting to {}", host),
+            ConnError::Tls(reason) => write!(f, "TLS handshake failed: {}", reason),
+            ConnError::TlsTimeout(host) => write!(f, "Timed out during the TLS handshake with {}", host),
+            ConnError::IdleTimeout(seconds) => write!(f, "Nothing was heard from the MUD for {} seconds", seconds),
+            ConnError::Closed => f.write_str("Connection closed")
+        }
+    }
+}
+
+impl std::error::Error for ConnError {}
+
+/// What the web client asked for in its connect message, or in
+/// `PHUD:CONNECT <host> <port> <tls> [option=value ...]` with the legacy protocol.
+/// With `--target-host`, the host, port and TLS setting come from the config instead,
+/// and the client may leave them out.
+pub struct ConnectDetails {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    /// `charset=<name>`: use this charset instead of UTF-8 or whatever the MUD negotiates.
+    pub charset: Option<Charset>,
+    /// `color=<true|false>`: overrides `--no-color` for this connection.
+    pub color: Option<bool>,
+    /// `renderer=<html|text|spans>`: the form MUD output is sent in.
+    pub renderer: Format
+}
+
+impl ConnectDetails {
+    fn new(target: Option<&Target>, host: Option<&str>, port: Option<u16>, tls: Option<bool>) -> Result<Self> {
+        let (host, port, tls) = match target {
+            // Whatever the client asked for, only the target can be reached.
+            Some(Target { host, port, tls }) => (host.clone(), *port, *tls),
+            None => (
+                host.context("Invalid host")?.to_string(),
+                port.context("Invalid port")?,
+                tls.context("Invalid TLS value (true, false)")?
+            )
+        };
+        Ok(Self { host, port, tls, charset: None, color: None, renderer: Format::Html })
+    }
+
+    fn set_option(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "charset" => self.charset = Some(Charset::from_label(value).context("Unknown charset")?),
+            "color" => self.color = Some(value.parse().context("Invalid color value (true, false)")?),
+            "renderer" => self.renderer = Format::from_name(value).context("Unknown renderer (html, text, spans)")?,
+            _ => bail!("Unknown connection option {}", key)
+        }
+        Ok(())
+    }
+
+    /// Whether MUD output is shown in color, going by `--no-color` unless the
+    /// client said otherwise.
+    fn use_color(&self, config: &Config) -> bool {
+        self.color.unwrap_or(!config.no_color)
+    }
+}
+
+/// Parses the arguments of `PHUD:CONNECT`. With a `target`, the host, port
+/// and TLS setting may be left out.
+fn parse_connect(args: &str, target: Option<&Target>) -> Result<ConnectDetails> {
+    let mut parser = args.split(' ').peekable();
+    let mut details = if target.is_some() {
+        while parser.next_if(|arg| !arg.contains('=')).is_some() {}
+        ConnectDetails::new(target, None, None, None)?
+    } else {
+        let host = parser.next().context("Invalid host")?;
         let port = parser.next().context("Invalid port")?.parse()?;
         let tls = parser.next().context("Invalid TLS value (true, false)")?.parse()?;
-        Ok((host, port, tls))
-    } else {
-        bail!("Command unimplemented");
+        ConnectDetails::new(None, Some(host), Some(port), Some(tls))?
+    };
+    for option in parser.filter(|option| !option.is_empty()) {
+        let (key, value) = option.split_once('=').context("Connection options must be given as key=value")?;
+        details.set_option(key, value)?;
     }
+    Ok(details)
 }
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, sleep_until, timeout, Instant};
 use libtelnet_rs::Parser;
-use libtelnet_rs::events::TelnetEvents;
+use libtelnet_rs::compatibility::{CompatibilityEntry, CompatibilityTable};
+use libtelnet_rs::events::{TelnetEvents, TelnetIAC, TelnetNegotiation, TelnetSubnegotiation};
+use libtelnet_rs::telnet::op_command::{IAC, SB, SE, WILL, WONT, DO, DONT, GA, EOR, NOP};
+
+/// How long to wait for a connection attempt before starting the next one
+/// alongside it, the "Connection Attempt Delay" of RFC 8305.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
 
-fn get_ip_ensure_non_local(host: &str) -> Result<IpAddr> {
-    let ip = format!("{}:443", host)
-        .to_socket_addrs()?
-        .next().context("Unable to resolve IP")?.ip();
-    if !get_config().allow_private_connections {
+/// Checks that web clients may connect to `addr`, one of the addresses
+/// `host` resolved to. The `--target-host` may be on a private network, as
+/// the operator chose it rather than a web client.
+fn check_address(config: &Config, host: &str, addr: SocketAddr) -> Result<()> {
+    let ip = addr.ip();
+    let is_target = config.target.as_ref().map_or(false, |target| target.host.eq_ignore_ascii_case(host));
+    if !config.allow_private_connections && !is_target {
         let equal_local_ip = if let Some(local_ip) = crate::localip::get() {
             ip.eq(local_ip)
         } else {
@@ -76,34 +417,126 @@ fn get_ip_ensure_non_local(host: &str) -> Result<IpAddr> {
             bail!("The provided host cannot be globally routed");
         }
     };
-    Ok(ip)
+    config.policy.check(host, addr.port(), ip)
+}
+
+/// Resolves `host` without blocking, and returns the addresses web clients
+/// may connect to, in the order `interleave` puts them.
+async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let mut rejection = None;
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await
+        .map_err(|_| ConnError::Resolve(host.to_string()))?
+        .filter(|&addr| match check_address(get_config(), host, addr) {
+            Ok(()) => true,
+            Err(err) => {
+                debug!("Not connecting to {} for {}:{}: {}", addr, host, port, err);
+                rejection.get_or_insert(err);
+                false
+            }
+        })
+        .collect();
+    if addrs.is_empty() {
+        bail!(match rejection {
+            Some(err) => ConnError::Rejected(err.to_string()),
+            None => ConnError::Resolve(host.to_string())
+        });
+    }
+    Ok(interleave(addrs))
+}
+
+/// Orders `addrs` as RFC 8305 suggests: alternating between IPv6 and IPv4,
+/// starting with the family the resolver preferred.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = addrs.first().map_or(false, |addr| addr.is_ipv6());
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == first_is_v6);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut ordered = Vec::new();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (first, second) => ordered.extend(first.into_iter().chain(second))
+        }
+    }
+    ordered
+}
+
+/// Aborts the connection attempts that are still running when dropped.
+struct Attempts(Vec<JoinHandle<()>>);
+
+impl Drop for Attempts {
+    fn drop(&mut self) {
+        for attempt in &self.0 {
+            attempt.abort();
+        }
+    }
+}
+
+/// Connects to the first of `addrs` to answer, RFC 8305 style. Attempts are
+/// started in order, each one `ATTEMPT_DELAY` after the last or as soon as
+/// the last one fails, and the rest are abandoned once one succeeds.
+async fn connect_any(addrs: Vec<SocketAddr>) -> Result<TcpStream> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut attempts = Attempts(Vec::new());
+    let mut addrs = addrs.into_iter().peekable();
+    let mut running = 0;
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = addrs.next() {
+            let tx = tx.clone();
+            attempts.0.push(tokio::spawn(async move {
+                let _ = tx.send(TcpStream::connect(addr).await.map_err(|err| format!("Could not connect to {}: {}", addr, err)));
+            }));
+            running += 1;
+        }
+        if running == 0 {
+            bail!(ConnError::Connect(last_error.unwrap_or_default()));
+        }
+        tokio::select! {
+            Some(result) = rx.recv() => {
+                running -= 1;
+                match result {
+                    Ok(stream) => return Ok(stream),
+                    Err(err) => last_error = Some(err)
+                }
+            },
+            _ = sleep(ATTEMPT_DELAY), if addrs.peek().is_some() => {}
+        }
+    }
 }
 
 use tokio_native_tls::{TlsConnector, TlsStream};
 
-enum MaybeTls {
+pub enum MaybeTls {
     Normal(TcpStream),
     Tls(TlsStream<TcpStream>)
 }
 
 impl MaybeTls {
-    async fn connect(host: &str, ip: &str, port: u16, tls: bool) -> Result<Self> {
-        let socket = TcpStream::connect(format!("{}:{}", ip, port)).await?;
+    /// Connects to `host:port` on one of the addresses web clients may reach.
+    /// Failures, including running out of `--connect-timeout` or `--tls-timeout`,
+    /// are `ConnError`s.
+    pub async fn connect(host: &str, port: u16, tls: bool) -> Result<Self> {
+        let config = get_config();
+        let socket = timeout(config.connect_timeout, async { connect_any(resolve(host, port).await?).await }).await
+            .map_err(|_| ConnError::ConnectTimeout(format!("{}:{}", host, port)))??;
         if !tls {
             Ok(Self::Normal(socket))
         } else {
             let mut cx = tokio_native_tls::native_tls::TlsConnector::builder();
-            if get_config().allow_invalid_tls {
+            if config.allow_invalid_tls {
                 cx.danger_accept_invalid_certs(true);
                 cx.danger_accept_invalid_hostnames(true);
             }
-            let cx = cx.build()?;
+            let cx = cx.build().map_err(|err| ConnError::Tls(err.to_string()))?;
             let cx = TlsConnector::from(cx);
-            let socket = cx.connect(host, socket).await?;
+            let socket = timeout(config.tls_timeout, cx.connect(host, socket)).await
+                .map_err(|_| ConnError::TlsTimeout(format!("{}:{}", host, port)))?
+                .map_err(|err| ConnError::Tls(err.to_string()))?;
             Ok(Self::Tls(socket))
         }
     }
-    async fn read(&mut self, dest: &mut [u8]) -> Result<usize> {
+    pub async fn read(&mut self, dest: &mut [u8]) -> Result<usize> {
         match self {
             MaybeTls::Normal(stream) => {
                 Ok(stream.read(dest).await?)
@@ -113,7 +546,7 @@ impl MaybeTls {
             }
         }
     }
-    async fn write_all(&mut self, src: &[u8]) -> Result<()> {
+    pub async fn write_all(&mut self, src: &[u8]) -> Result<()> {
         match self {
             MaybeTls::Normal(stream) => {
                 Ok(stream.write_all(src).await?)
@@ -125,93 +558,780 @@ impl MaybeTls {
     } 
 }
 
-async fn telnet_handler(host: String, port: u16, parent: &mut impl ConnParent, mut rx: mpsc::UnboundedReceiver<String>, tls: bool) -> Result<()> {
-    let ip = get_ip_ensure_non_local(&host)?;
-    let mut conn = MaybeTls::connect(&host, &ip.to_string(), port, tls).await?;
+/// Lets the MUD turn `option` on with WILL and then send subnegotiations for it.
+/// libtelnet-rs drops subnegotiations for options that aren't also enabled on
+/// our side, so the option starts out that way, in case the subnegotiation
+/// arrives in the same read as the WILL.
+pub fn support_server_option(options: &mut CompatibilityTable, option: u8) {
+    options.set_option(option, CompatibilityEntry::new(true, true, true, false));
+}
+
+/// Lets the MUD send subnegotiations for `option` now that it has turned it on.
+/// For options we also offer ourselves, like CHARSET, this can't be set from
+/// the start, as libtelnet-rs would then ignore the MUD's DO.
+fn accept_subnegotiations(telnet: &mut Parser, option: u8) {
+    let mut entry = telnet.options.get_option(option);
+    entry.local_state = true;
+    telnet.options.set_option(option, entry);
+}
+
+/// The telnet options we support on the connection to the MUD, apart from MCCP.
+fn telnet_options() -> CompatibilityTable {
+    let mut options = CompatibilityTable::new();
+    support_server_option(&mut options, GMCP);
+    support_server_option(&mut options, MSDP);
+    support_server_option(&mut options, ATCP);
+    options.support_remote(MXP);
+    options.support_remote(ECHO);
+    options.support_remote(TELOPT_EOR);
+    options.support_local(NAWS);
+    options.support_local(TTYPE);
+    options.support(CHARSET);
+    options
+}
+
+/// The socket to the MUD, plus any MCCP compression negotiated on it.
+struct MudConn {
+    stream: MaybeTls,
+    #[cfg(feature = "mccp")]
+    mccp: mccp::Compression
+}
+
+impl MudConn {
+    fn new(stream: MaybeTls) -> Self {
+        Self {
+            stream,
+            #[cfg(feature = "mccp")]
+            mccp: mccp::Compression::new()
+        }
+    }
+    async fn read(&mut self, dest: &mut [u8]) -> Result<usize> {
+        self.stream.read(dest).await
+    }
+    /// Hands bytes from `read` to the telnet parser, decompressing as needed.
+    fn receive(&mut self, telnet: &mut Parser, data: &[u8]) -> Result<Vec<TelnetEvents>> {
+        #[cfg(feature = "mccp")]
+        return self.mccp.receive(telnet, data);
+        #[cfg(not(feature = "mccp"))]
+        Ok(telnet.receive(data))
+    }
+    async fn write_all(&mut self, src: &[u8]) -> Result<()> {
+        #[cfg(feature = "mccp")]
+        if let Some(compressed) = self.mccp.outgoing(src)? {
+            return self.stream.write_all(&compressed).await;
+        }
+        self.stream.write_all(src).await
+    }
+    /// Tells the MUD that everything we send from now on is compressed,
+    /// unless that is the case already.
+    #[cfg(feature = "mccp")]
+    async fn start_mccp3(&mut self) -> Result<()> {
+        if self.mccp.deflating() {
+            return Ok(());
+        }
+        self.write_all(&subnegotiation(MCCP3, &[])).await?;
+        self.mccp.start_deflate();
+        Ok(())
+    }
+}
+
+/// Appends `data` to `out`, doubling any IAC bytes.
+fn escape_iac(out: &mut Vec<u8>, data: &[u8]) {
+    for &byte in data {
+        if byte == IAC {
+            out.push(IAC);
+        }
+        out.push(byte);
+    }
+}
+
+/// Wraps `data` in `IAC SB <option> ... IAC SE`, escaping any IAC bytes.
+fn subnegotiation(option: u8, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 5);
+    out.extend_from_slice(&[IAC, SB, option]);
+    escape_iac(&mut out, data);
+    out.extend_from_slice(&[IAC, SE]);
+    out
+}
+
+/// A line of user input, encoded in the connection's charset.
+fn input_line(text: &str, charset: Charset) -> Vec<u8> {
+    let encoded = charset.encode(text);
+    let mut out = Vec::with_capacity(encoded.len() + 2);
+    escape_iac(&mut out, &encoded);
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+/// Telnet "Are You There" command, asking the MUD whether it is still there.
+/// libtelnet-rs doesn't define it.
+const AYT: u8 = 246;
+
+/// Telnet option number for ECHO. The MUD sends `WILL ECHO` to take over
+/// echoing, usually while a password is being entered.
+const ECHO: u8 = 1;
+
+/// Telnet option number for EOR, which lets the MUD mark prompts with `IAC EOR`.
+const TELOPT_EOR: u8 = 25;
+
+/// Telnet option number for NAWS (window size).
+const NAWS: u8 = 31;
+
+/// Builds the NAWS subnegotiation for a window of `cols` by `rows` characters.
+fn naws(cols: u16, rows: u16) -> Vec<u8> {
+    let mut data = Vec::with_capacity(4);
+    data.extend_from_slice(&cols.to_be_bytes());
+    data.extend_from_slice(&rows.to_be_bytes());
+    subnegotiation(NAWS, &data)
+}
+
+/// Parses `<cols> <rows>` as sent by the web client in `PHUD:NAWS`.
+fn parse_window_size(args: &str) -> Option<(u16, u16)> {
+    let mut parser = args.split_whitespace();
+    let cols: u16 = parser.next()?.parse().ok()?;
+    let rows: u16 = parser.next()?.parse().ok()?;
+    if cols == 0 || rows == 0 {
+        return None;
+    }
+    Some((cols, rows))
+}
+
+/// Splits off the text after the last line break, which is the prompt when
+/// the MUD follows it with GA or EOR.
+fn split_prompt(text: &[u8]) -> (&[u8], &[u8]) {
+    match text.iter().rposition(|&c| c == b'\n' || c == b'\r') {
+        Some(i) => text.split_at(i + 1),
+        None => (&[], text)
+    }
+}
+
+/// Sends rendered MUD output to the web client, as a prompt or not. `tail`
+/// is what the rules do with the text after its last line break, which
+/// includes any they held back before.
+fn send_rendered(parent: &impl ConnParent, protocol: Protocol, prompt: bool, text: &[u8], tail: Tail, decoder: &mut Decoder, renderer: &mut dyn Renderer) {
+    if text.is_empty() && tail == Tail::Hold {
+        return;
+    }
+    for output in renderer.render(&decoder.decode(text), tail) {
+        match output {
+            Output::Text(json) => send(parent, protocol, ServerMessage::Output { prompt, data: &json }),
+            Output::Clear => send(parent, protocol, ServerMessage::Clear)
+        }
+    }
+}
+
+/// How long an unfinished line waits for the rest of it before the rules let
+/// it be shown, as it may be a prompt without GA or EOR. The pieces of a line
+/// split across reads normally arrive well within this.
+const PROMPT_DELAY: Duration = Duration::from_millis(100);
+
+/// Sends the complete lines in `text` and removes them. With `hold_partial`,
+/// the text after the last line break is left for the next read, as it may be
+/// a prompt whose GA or EOR hasn't arrived yet. Otherwise it is passed on, and
+/// the rules hold it back until the line is finished.
+fn send_text(parent: &impl ConnParent, protocol: Protocol, text: &mut Vec<u8>, hold_partial: bool, decoder: &mut Decoder, renderer: &mut dyn Renderer) {
+    let (lines, partial) = split_prompt(text);
+    let end = if hold_partial && partial.len() <= MAX_HELD { lines.len() } else { text.len() };
+    send_rendered(parent, protocol, false, &text[..end], Tail::Hold, decoder, renderer);
+    text.drain(..end);
+}
+
+/// Sends `text`, which the MUD ended with GA or EOR, and removes it. Its last
+/// line is the prompt, for the web client to pin in its status line.
+fn send_prompt(parent: &impl ConnParent, protocol: Protocol, text: &mut Vec<u8>, decoder: &mut Decoder, renderer: &mut dyn Renderer) {
+    let (lines, prompt) = split_prompt(text);
+    send_rendered(parent, protocol, false, lines, Tail::Hold, decoder, renderer);
+    send_rendered(parent, protocol, true, prompt, Tail::End, decoder, renderer);
+    text.clear();
+}
+
+/// Tells older web clients to clear their output, when the MUD clears the screen.
+static CLEAR: &'static str = r#"{"clear":true}"#;
+
+/// Tells older web clients about the MUD connection. `ATCP_Disconnect` is
+/// for the `handle_ATCP` hook, which predates `conn_status`.
+static CONNECTED: &'static str = r#"{"conn_status":"connected"}"#;
+static DISCONNECTED: &'static str = r#"{"ATCP_Disconnect":"true","conn_status":"disconnected"}"#;
+
+/// Telnet options the web client is told about as the MUD turns them on and off.
+static OPTION_NAMES: [(u8, &'static str); 6] = [
+    (ECHO, "echo"), (NAWS, "naws"), (MXP, "mxp"), (GMCP, "gmcp"), (MSDP, "msdp"), (ATCP, "atcp")
+];
+
+/// What to tell the web client after the MUD sent `command` for `option`, if
+/// the client tracks it. The state is read from the option table once the
+/// parser has answered, as the request may have been refused.
+fn option_state(telnet: &Parser, command: u8, option: u8) -> Option<(&'static str, bool)> {
+    let &(_, name) = OPTION_NAMES.iter().find(|(number, _)| *number == option)?;
+    let entry = telnet.options.get_option(option);
+    // We send NAWS once the MUD asks with DO. The other options are the MUD's own.
+    match command {
+        DO | DONT if option == NAWS => Some((name, entry.local_state)),
+        WILL | WONT if option != NAWS => Some((name, entry.remote_state)),
+        _ => None
+    }
+}
+
+/// Whether the MUD's `command` has just turned `option` on, so we should
+/// greet it. libtelnet-rs reports WILL even when it refused the option or it
+/// was on already, so this goes by the option table. `on` remembers which
+/// options were on after the last WILL or WONT.
+fn turned_on(telnet: &Parser, on: &mut [bool; 256], command: u8, option: u8) -> bool {
+    if command != WILL && command != WONT {
+        return false;
+    }
+    let was_on = std::mem::replace(&mut on[option as usize], telnet.options.get_option(option).remote_state);
+    on[option as usize] && !was_on
+}
+
+/// Splits `PHUD:<COMMAND> <args>` into the command and its arguments.
+fn parse_client_command(msg: &str) -> Option<(&str, &str)> {
+    let rest = msg.strip_prefix("PHUD:")?;
+    Some(rest.split_once(' ').unwrap_or((rest, "")))
+}
+
+async fn telnet_handler(details: ConnectDetails, protocol: Protocol, parent: &mut impl ConnParent, mut rx: mpsc::UnboundedReceiver<String>) -> Result<()> {
+    let ConnectDetails { host, port, tls, .. } = &details;
+    let mut conn = MudConn::new(MaybeTls::connect(host, *port, *tls).await?);
+    send(parent, protocol, ServerMessage::Connected);
     //let mut conn = TcpStream::connect(format!("{}:{}", host, port)).await?;
     
-    let mut telnet = Parser::new();
+    let mut options = telnet_options();
+    #[cfg(feature = "mccp")]
+    if !get_config().no_mccp {
+        mccp::support(&mut options);
+    }
+    let mut telnet = Parser::with_support(options);
     let mut buf = Vec::with_capacity(2048);
     unsafe { buf.set_len(2048); }
+    // Last size reported by the web client, and whether the MUD has asked for it.
+    let mut window_size: Option<(u16, u16)> = None;
+    let mut naws_enabled = false;
+    let mut terminal_type = TerminalType::new();
+    let client_name = &get_config().client_name;
+    let mut decoder = Decoder::new(details.charset.unwrap_or(Charset::Utf8));
+    let color = details.use_color(get_config());
+    let mut renderer = details.renderer.renderer(color, RuleSet::for_host(host));
+    let Config { idle_timeout, keepalive, keepalive_ayt, .. } = *get_config();
+    // When the MUD last sent anything, and when we last sent a keepalive.
+    let mut last_heard = Instant::now();
+    let mut last_keepalive = last_heard;
+    // Text is collected so a prompt ended by GA/EOR can be split off. Once the
+    // MUD has marked a prompt that way, a line that isn't finished by the end
+    // of a read is kept for the next one, as its GA/EOR may come later. Until
+    // then it goes to the renderer, and is shown after `PROMPT_DELAY` if the
+    // rules are still holding it back.
+    let mut text: Vec<u8> = Vec::new();
+    let mut prompts_marked = false;
+    let mut show_partial_at: Option<Instant> = None;
+    // The MUD's options as of its last WILL or WONT for each, see `turned_on`.
+    let mut remote_on = [false; 256];
 
     loop {
+        let idle_deadline = idle_timeout.map(|idle_timeout| last_heard + idle_timeout);
+        let keepalive_at = keepalive.map(|keepalive| last_heard.max(last_keepalive) + keepalive);
         tokio::select! {
             bytes_read = conn.read(&mut buf) => {
                 let bytes_read: usize = bytes_read?;
                 if bytes_read == 0 {
-                    bail!("Connection closed");
+                    // Whatever was held back won't be finished now.
+                    send_rendered(parent, protocol, false, &text, Tail::End, &mut decoder, renderer.as_mut());
+                    bail!(ConnError::Closed);
                 }
-                let events = telnet.receive(&buf[..bytes_read]);
+                last_heard = Instant::now();
+                let events = conn.receive(&mut telnet, &buf[..bytes_read])?;
+                // Replies that must go out after the parser's own negotiation responses.
+                let mut deferred: Vec<Vec<u8>> = Vec::new();
+                #[cfg(feature = "mccp")]
+                let mut start_mccp3 = false;
                 for event in events {
                     match event {
                         TelnetEvents::DataReceive(data) => {
-                            let data = strip_telnet(
-                                String::from_utf8_lossy(&data).to_string()
-                            );
-                            try_json(parent, ClientMessage {
-                                message: data
-                            });
+                            text.extend_from_slice(&data);
+                            continue;
                         },
                         TelnetEvents::DataSend(to_send) => {
                             conn.write_all(&to_send).await?;
+                            continue;
+                        },
+                        TelnetEvents::IAC(TelnetIAC { command: GA | EOR, .. }) => {
+                            prompts_marked = true;
+                            send_prompt(parent, protocol, &mut text, &mut decoder, renderer.as_mut());
+                            continue;
                         },
                         _ => {}
                     }
+                    // Keep the text in order with anything else sent to the client.
+                    send_text(parent, protocol, &mut text, prompts_marked, &mut decoder, renderer.as_mut());
+                    match event {
+                        TelnetEvents::Negotiation(TelnetNegotiation { command, option }) => {
+                            if let Some((name, enabled)) = option_state(&telnet, command, option) {
+                                send(parent, protocol, ServerMessage::TelnetOption(name, enabled));
+                            }
+                            let turned_on = turned_on(&telnet, &mut remote_on, command, option);
+                            match (command, option) {
+                                (WILL, GMCP) if turned_on => deferred.push(subnegotiation(GMCP, &gmcp::hello(client_name))),
+                                (WILL, MSDP) if turned_on => deferred.push(subnegotiation(MSDP, &msdp::hello(client_name))),
+                                (WILL, ATCP) if turned_on => deferred.push(subnegotiation(ATCP, &atcp::hello(client_name))),
+                                (DO, TTYPE) => terminal_type.reset(),
+                                (WILL, MXP) => renderer.set_mxp(true),
+                                (WONT, MXP) => renderer.set_mxp(false),
+                                (DO, NAWS) => {
+                                    naws_enabled = true;
+                                    if let Some((cols, rows)) = window_size {
+                                        deferred.push(naws(cols, rows));
+                                    }
+                                },
+                                (DONT, NAWS) => naws_enabled = false,
+                                (WILL, CHARSET) => accept_subnegotiations(&mut telnet, CHARSET),
+                                (DO, CHARSET) => deferred.push(subnegotiation(CHARSET, &charset::request(details.charset))),
+                                #[cfg(feature = "mccp")]
+                                (WILL, MCCP3) if turned_on => start_mccp3 = true,
+                                _ => {}
+                            }
+                        },
+                        TelnetEvents::Subnegotiation(TelnetSubnegotiation { option, buffer }) => {
+                            match option {
+                                GMCP => {
+                                    if let Some(msg) = gmcp::decode(&buffer) {
+                                        send(parent, protocol, ServerMessage::Gmcp(msg));
+                                    }
+                                },
+                                MSDP => send(parent, protocol, ServerMessage::Msdp(&buffer)),
+                                CHARSET => {
+                                    if let Some(accepted) = charset::accepted(&buffer) {
+                                        decoder.set_charset(accepted);
+                                    } else if let Some((reply, chosen)) = charset::respond(&buffer, details.charset) {
+                                        conn.write_all(&subnegotiation(CHARSET, &reply)).await?;
+                                        if let Some(chosen) = chosen {
+                                            decoder.set_charset(chosen);
+                                        }
+                                    }
+                                    debug!("Using charset {} for {}:{}", decoder.charset().name(), host, port);
+                                },
+                                TTYPE => {
+                                    if let Some(payload) = terminal_type.respond(&buffer, client_name, decoder.charset(), color) {
+                                        conn.write_all(&subnegotiation(TTYPE, &payload)).await?;
+                                    }
+                                },
+                                ATCP => send(parent, protocol, ServerMessage::Atcp(&buffer)),
+                                _ => {}
+                            }
+                        },
+                        _ => {}
+                    }
+                }
+                send_text(parent, protocol, &mut text, prompts_marked, &mut decoder, renderer.as_mut());
+                if !prompts_marked {
+                    show_partial_at = Some(Instant::now() + PROMPT_DELAY);
+                }
+                for to_send in deferred {
+                    conn.write_all(&to_send).await?;
+                }
+                #[cfg(feature = "mccp")]
+                if start_mccp3 {
+                    conn.start_mccp3().await?;
                 }
             },
             to_send = rx.recv() => {
                 let to_send = to_send.context("Client connection disconnected")?;
-                if let TelnetEvents::DataSend(to_send) = telnet.send_text(to_send.trim()) {
-                    conn.write_all(&to_send).await?;
+                match protocol.decode(&to_send) {
+                    Ok(ClientMessage::Input(text)) => conn.write_all(&input_line(&text, decoder.charset())).await?,
+                    Ok(ClientMessage::Gmcp(payload)) => conn.write_all(&subnegotiation(GMCP, &payload)).await?,
+                    Ok(ClientMessage::Msdp(payload)) => conn.write_all(&subnegotiation(MSDP, &payload)).await?,
+                    Ok(ClientMessage::Naws(cols, rows)) => {
+                        if naws_enabled && window_size != Some((cols, rows)) {
+                            conn.write_all(&naws(cols, rows)).await?;
+                        }
+                        window_size = Some((cols, rows));
+                    },
+                    Ok(ClientMessage::Disconnect) => return Ok(()),
+                    Ok(ClientMessage::Hello(_) | ClientMessage::Connect(_)) => {
+                        debug!("Ignoring handshake message from client, as it is already connected");
+                    },
+                    Err(err) => {
+                        debug!("Ignoring message from client: {}", err);
+                    }
                 }
+            },
+            _ = sleep_until(show_partial_at.unwrap_or(last_heard)), if show_partial_at.is_some() => {
+                show_partial_at = None;
+                send_rendered(parent, protocol, false, &[], Tail::Show, &mut decoder, renderer.as_mut());
+            },
+            _ = sleep_until(idle_deadline.unwrap_or(last_heard)), if idle_deadline.is_some() => {
+                bail!(ConnError::IdleTimeout(idle_timeout.unwrap_or_default().as_secs()));
+            },
+            // AYT asks the MUD to answer, NOP only makes sure the link still carries data.
+            _ = sleep_until(keepalive_at.unwrap_or(last_heard)), if keepalive_at.is_some() => {
+                conn.write_all(&[IAC, if keepalive_ayt { AYT } else { NOP }]).await?;
+                last_keepalive = Instant::now();
             }
         };
     }
 }
 
-//use lazy_static::lazy_static;
+/// A notice for older web clients, shown along with the MUD output.
+#[derive(SerJson)]
+struct TextMessage {
+    message: String
+}
 
-/*static TELNET_COLORS: [&'static str; 29] = ["[0m","[00m","[1m","[3m","[4m","[7m","[9m","[22m","[23m","[24m","[29m","[30m","[31m","[32m","[33m","[34m","[35m","[36m","[37m","[39m","[40m","[41m","[42m","[43m","[44m","[45m","[46m","[47m","[49m"]; 
-static TELNET_REPLS: [&'static str; 29] = ["</b></span>","</b></span>","<b>","","","<span class='tnc_inverse'>","","</b>","","","","<span class='tnc_black'>","<span class='tnc_red'>","<span class='tnc_green'>","<span class='tnc_yellow'>","<span class='tnc_blue'>","<span class='tnc_magenta'>","<span class='tnc_cyan'>","<span class='tnc_white'>","<span class='tnc_default'>","<span class='tnc_bg_black'>","<span class='tnc_bg_red'>","<span class='tnc_bg_green'>","<span class='tnc_bg_yellow'>","<span class='tnc_bg_blue'>","<span class='tnc_bg_magenta'>","<span class='tnc_bg_cyan'>","<span class='tnc_bg_white'>","<span class='tnc_bg_default'>"];*/
+/// Sent when the connection fails. `error` is one of the `ConnError::kind`s,
+/// or `error` for anything else, and `message` is shown to the user.
+#[derive(SerJson)]
+struct ErrorMessage {
+    error: String,
+    message: String
+}
 
-fn strip_telnet(mut the_item: String) -> String {
-    the_item = the_item
-        .replace("&", "&amp;")
-        .replace("<", "&lt;")
-        .replace(">", "&gt;")
-        .replace("\t", "     ");
+/// Tells the web client whether to echo (and show) what the user types.
+#[derive(SerJson)]
+struct EchoMessage {
+    local_echo: String
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::rules::Action;
 
-    /*lazy_static! {
-        static ref TELNET_COMBO: Regex = Regex::new(r"(?i)\[(\d+);").unwrap();
+    /// The events for `data` arriving in a single read on a new connection.
+    fn receive(data: &[u8]) -> Vec<TelnetEvents> {
+        Parser::with_support(telnet_options()).receive(data)
     }
-    TELNET_COMBO.replace_all("hello", |cap: &regex::Captures| {
-        format!("{}", cap.get(0).unwrap().as_str())
-    });
-    while TELNET_COMBO.is_match(&the_item) {
-        the_item = TELNET_COMBO.replace_all(&the_item, "[${1}m\x1B[").to_string();
+
+    fn replied(events: &[TelnetEvents], reply: &[u8]) -> bool {
+        events.iter().any(|event| matches!(event, TelnetEvents::DataSend(data) if &data[..] == reply))
     }
-    for (telnet_color, telnet_rep) in TELNET_COLORS.iter().zip(TELNET_REPLS.iter()).map(|(a,b)| (*a, *b)) {
-        the_item = the_item.replace(&format!("\x1B{}", telnet_color), 
-            if get_config().no_color { "" } else { telnet_rep });
-    }*/
 
-    the_item = ansi2html(&the_item);
+    /// The payload of the first subnegotiation for `option`.
+    fn subnegotiation_for(events: &[TelnetEvents], option: u8) -> Option<Vec<u8>> {
+        events.iter().find_map(|event| match event {
+            TelnetEvents::Subnegotiation(TelnetSubnegotiation { option: found, buffer }) if *found == option => Some(buffer.to_vec()),
+            _ => None
+        })
+    }
 
-    the_item
-        .replace("\x1B", "")
-        .replace("\r\n", "<br>")
-        .replace("\n\r", "<br>")
-        .replace("\r", "<br>")
-        .replace("\n", "<br>")
-        .replace("\u{00FF}\u{00F9}", "<br>")
-        .replace(char::is_control, "")
-        .replace("_-SYSTEM: CHAT-_", "")
-        .replace("`", "'")
-}
+    #[test]
+    fn forwards_gmcp() {
+        let mut data = vec![IAC, WILL, GMCP];
+        data.extend(subnegotiation(GMCP, br#"Char.Vitals {"hp": 100, "maxhp": 120}"#));
+        let events = receive(&data);
+        assert!(replied(&events, &[IAC, DO, GMCP]));
+        let msg = gmcp::decode(&subnegotiation_for(&events, GMCP).unwrap()).unwrap();
+        assert_eq!(
+            Protocol::Legacy.encode(ServerMessage::Gmcp(msg)).unwrap(),
+            r#"{"gmcp":{"package":"Char.Vitals","data":"{\"hp\": 100, \"maxhp\": 120}"}}"#
+        );
+    }
 
-use nanoserde::SerJson;
+    #[test]
+    fn forwards_msdp() {
+        let mut data = vec![IAC, WILL, MSDP];
+        data.extend(subnegotiation(MSDP, b"\x01HEALTH\x02100\x01HEALTH_MAX\x02120"));
+        let events = receive(&data);
+        assert!(replied(&events, &[IAC, DO, MSDP]));
+        assert_eq!(
+            msdp::to_client_json(&subnegotiation_for(&events, MSDP).unwrap()),
+            r#"{"msdp":{"HEALTH":"100","HEALTH_MAX":"120"}}"#
+        );
+    }
 
-#[derive(SerJson)]
-struct ClientMessage {
-    message: String
-}
\ No newline at end of file
+    #[test]
+    fn forwards_atcp() {
+        let mut data = vec![IAC, WILL, ATCP];
+        data.extend(subnegotiation(ATCP, b"Room.Exits north,se,out"));
+        let events = receive(&data);
+        assert!(replied(&events, &[IAC, DO, ATCP]));
+        assert_eq!(
+            atcp::to_client_json(&subnegotiation_for(&events, ATCP).unwrap()).unwrap(),
+            r#"{"ATCP_Room_Exits":["n","se","out"]}"#
+        );
+    }
+
+    #[test]
+    fn answers_charset_request_after_will() {
+        let mut telnet = Parser::with_support(telnet_options());
+        let events = telnet.receive(&[IAC, WILL, CHARSET]);
+        assert!(replied(&events, &[IAC, DO, CHARSET]));
+        accept_subnegotiations(&mut telnet, CHARSET);
+        let events = telnet.receive(&subnegotiation(CHARSET, b"\x01;ISO-8859-1;UTF-8"));
+        let (reply, chosen) = charset::respond(&subnegotiation_for(&events, CHARSET).unwrap(), None).unwrap();
+        assert_eq!(reply, b"\x02UTF-8");
+        assert!(chosen == Some(Charset::Utf8));
+    }
+
+    #[test]
+    fn offers_charset_on_do() {
+        let events = receive(&[IAC, DO, CHARSET]);
+        assert!(replied(&events, &[IAC, WILL, CHARSET]));
+    }
+
+    #[test]
+    fn refuses_do_echo_without_reporting_echo() {
+        let mut telnet = Parser::with_support(telnet_options());
+        let events = telnet.receive(&[IAC, DO, ECHO]);
+        assert!(replied(&events, &[IAC, WONT, ECHO]));
+        assert_eq!(option_state(&telnet, DO, ECHO), None);
+        let events = telnet.receive(&[IAC, WILL, ECHO]);
+        assert!(replied(&events, &[IAC, DO, ECHO]));
+        assert_eq!(option_state(&telnet, WILL, ECHO), Some(("echo", true)));
+        telnet.receive(&[IAC, WONT, ECHO]);
+        assert_eq!(option_state(&telnet, WONT, ECHO), Some(("echo", false)));
+    }
+
+    #[test]
+    fn greets_option_once() {
+        let mut telnet = Parser::with_support(telnet_options());
+        let mut on = [false; 256];
+        telnet.receive(&[IAC, WILL, GMCP]);
+        assert!(turned_on(&telnet, &mut on, WILL, GMCP));
+        telnet.receive(&[IAC, WILL, GMCP]);
+        assert!(!turned_on(&telnet, &mut on, WILL, GMCP));
+        telnet.receive(&[IAC, WONT, GMCP]);
+        assert!(!turned_on(&telnet, &mut on, WONT, GMCP));
+        telnet.receive(&[IAC, WILL, GMCP]);
+        assert!(turned_on(&telnet, &mut on, WILL, GMCP));
+    }
+
+    #[cfg(feature = "mccp")]
+    #[test]
+    fn ignores_refused_mccp3() {
+        // As with --no-mccp, where MCCP isn't among the supported options.
+        let mut telnet = Parser::with_support(telnet_options());
+        let events = telnet.receive(&[IAC, WILL, MCCP3]);
+        assert!(replied(&events, &[IAC, DONT, MCCP3]));
+        assert!(!turned_on(&telnet, &mut [false; 256], WILL, MCCP3));
+    }
+
+    #[test]
+    fn reports_naws_when_asked_for() {
+        let mut telnet = Parser::with_support(telnet_options());
+        let events = telnet.receive(&[IAC, DO, NAWS]);
+        assert!(replied(&events, &[IAC, WILL, NAWS]));
+        assert_eq!(option_state(&telnet, DO, NAWS), Some(("naws", true)));
+        telnet.receive(&[IAC, WILL, NAWS]);
+        assert_eq!(option_state(&telnet, WILL, NAWS), None);
+    }
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn interleaves_address_families() {
+        assert_eq!(
+            interleave(addrs(&["[2001:db8::1]:23", "[2001:db8::2]:23", "[2001:db8::3]:23", "192.0.2.1:23", "192.0.2.2:23"])),
+            addrs(&["[2001:db8::1]:23", "192.0.2.1:23", "[2001:db8::2]:23", "192.0.2.2:23", "[2001:db8::3]:23"])
+        );
+        assert_eq!(
+            interleave(addrs(&["192.0.2.1:23", "192.0.2.2:23", "[2001:db8::1]:23"])),
+            addrs(&["192.0.2.1:23", "[2001:db8::1]:23", "192.0.2.2:23"])
+        );
+    }
+
+    #[test]
+    fn keeps_order_of_single_family() {
+        let list = addrs(&["192.0.2.1:23", "192.0.2.2:23", "192.0.2.3:23"]);
+        assert_eq!(interleave(list.clone()), list);
+        let list = addrs(&["[2001:db8::1]:23", "[2001:db8::2]:23"]);
+        assert_eq!(interleave(list.clone()), list);
+    }
+
+    #[actix_web::test]
+    async fn connects_to_next_address_when_refused() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        // Nothing listens on a port that was just given up.
+        let refused = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let stream = connect_any(vec![refused, listener.local_addr().unwrap()]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+        let err = connect_any(vec![refused]).await.unwrap_err();
+        assert_eq!(error_kind(&err), "connect");
+    }
+
+    #[actix_web::test]
+    async fn times_out_connecting() {
+        crate::config::set_test_config();
+        // Whether a non-routable address answers depends on the network the
+        // tests run on. A listener with a full backlog doesn't answer either.
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut queued = Vec::new();
+        while let Ok(stream) = timeout(Duration::from_millis(100), TcpStream::connect(("127.0.0.1", port))).await {
+            queued.push(stream.unwrap());
+        }
+        let err = MaybeTls::connect("127.0.0.1", port, false).await.err().unwrap();
+        assert_eq!(error_kind(&err), "connect_timeout");
+    }
+
+    #[actix_web::test]
+    async fn drops_idle_connection() {
+        crate::config::set_test_config();
+        // Takes the connection but never sends anything.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tx.send(format!("PHUD:CONNECT 127.0.0.1 {} false", listener.local_addr().unwrap().port())).unwrap();
+        let err = handle_conn(&mut Client::default(), &mut Protocol::Legacy, rx).await.unwrap_err();
+        assert_eq!(error_kind(&err), "idle_timeout");
+    }
+
+    /// Records the messages for the web client.
+    #[derive(Default)]
+    struct Client(RefCell<Vec<String>>);
+
+    impl ConnParent for Client {
+        fn try_send(&self, msg: String) {
+            self.0.borrow_mut().push(msg);
+        }
+
+        fn try_close_conn(&self) {}
+    }
+
+    impl Client {
+        /// The messages sent since the last call.
+        fn sent(&self) -> Vec<String> {
+            self.0.take()
+        }
+    }
+
+    fn output(stream: &str, data: &str) -> String {
+        format!(r#"{{"type":"output","stream":"{}","data":"{}"}}"#, stream, data)
+    }
+
+    #[test]
+    fn splits_prompt_after_last_line_break() {
+        assert_eq!(split_prompt(b"Bob is here.\r\nHP: 10> "), (&b"Bob is here.\r\n"[..], &b"HP: 10> "[..]));
+        assert_eq!(split_prompt(b"HP: 10> "), (&b""[..], &b"HP: 10> "[..]));
+        assert_eq!(split_prompt(b"Bob is here.\n"), (&b"Bob is here.\n"[..], &b""[..]));
+    }
+
+    #[test]
+    fn joins_prompt_split_across_reads() {
+        let client = Client::default();
+        let mut decoder = Decoder::new(Charset::Utf8);
+        let mut renderer = Format::Text.renderer(false, RuleSet::default());
+        let mut text = b"Bob is here.\nHP: 1".to_vec();
+        send_text(&client, Protocol::Json(1), &mut text, true, &mut decoder, renderer.as_mut());
+        assert_eq!(client.sent(), [output("main", r"Bob is here.\n")]);
+        text.extend_from_slice(b"0> ");
+        send_prompt(&client, Protocol::Json(1), &mut text, &mut decoder, renderer.as_mut());
+        assert_eq!(client.sent(), [output("prompt", "HP: 10> ")]);
+        assert!(text.is_empty());
+    }
+
+    #[test]
+    fn holds_prompt_until_ga_arrives() {
+        let client = Client::default();
+        let mut decoder = Decoder::new(Charset::Utf8);
+        let mut renderer = Format::Text.renderer(false, RuleSet::default());
+        let mut text = b"HP: 10> ".to_vec();
+        send_text(&client, Protocol::Json(1), &mut text, true, &mut decoder, renderer.as_mut());
+        assert!(client.sent().is_empty());
+        // The GA arrives in the next read, on its own.
+        send_prompt(&client, Protocol::Json(1), &mut text, &mut decoder, renderer.as_mut());
+        assert_eq!(client.sent(), [output("prompt", "HP: 10> ")]);
+    }
+
+    #[test]
+    fn sends_long_unfinished_line() {
+        let client = Client::default();
+        let mut decoder = Decoder::new(Charset::Utf8);
+        let mut renderer = Format::Text.renderer(false, RuleSet::default());
+        let mut text = vec![b'x'; MAX_HELD];
+        send_text(&client, Protocol::Json(1), &mut text, true, &mut decoder, renderer.as_mut());
+        assert!(client.sent().is_empty());
+        text.push(b'x');
+        send_text(&client, Protocol::Json(1), &mut text, true, &mut decoder, renderer.as_mut());
+        assert_eq!(client.sent(), [output("main", &"x".repeat(MAX_HELD + 1))]);
+        assert!(text.is_empty());
+    }
+
+    #[test]
+    fn sends_unmarked_prompt_with_rules() {
+        let client = Client::default();
+        let mut decoder = Decoder::new(Charset::Utf8);
+        let mut renderer = Format::Text.renderer(false, RuleSet::from_rules(vec![(r"^\[OOC\]", Action::Gag)]));
+        let mut text = b"[OOC] Bob: hi\nHP: 10> ".to_vec();
+        send_text(&client, Protocol::Json(1), &mut text, false, &mut decoder, renderer.as_mut());
+        assert!(text.is_empty());
+        assert!(client.sent().is_empty());
+        // Once `PROMPT_DELAY` has passed without the rest of the line.
+        send_rendered(&client, Protocol::Json(1), false, &[], Tail::Show, &mut decoder, renderer.as_mut());
+        assert_eq!(client.sent(), [output("main", "HP: 10> ")]);
+    }
+
+    /// The payload of a GMCP or MSDP message from the web client.
+    fn client_payload(protocol: Protocol, msg: &str) -> Vec<u8> {
+        match protocol.decode(msg).unwrap() {
+            ClientMessage::Gmcp(payload) | ClientMessage::Msdp(payload) => payload,
+            _ => panic!("not a GMCP or MSDP message: {}", msg)
+        }
+    }
+
+    /// What a connect message from the web client asks for.
+    fn connect_details(protocol: Protocol, msg: &str) -> ConnectDetails {
+        match protocol.decode(msg).unwrap() {
+            ClientMessage::Connect(details) => details,
+            _ => panic!("not a connect message: {}", msg)
+        }
+    }
+
+    #[test]
+    fn uses_color_unless_turned_off() {
+        crate::config::set_test_config();
+        let color = crate::test_config(&[]).unwrap();
+        let no_color = crate::test_config(&["--no-color"]).unwrap();
+        let details = connect_details(Protocol::Legacy, "PHUD:CONNECT mud.example.org 23 false");
+        assert!(details.use_color(&color));
+        assert!(!details.use_color(&no_color));
+        // The client's choice wins over `--no-color` either way.
+        let details = connect_details(Protocol::Legacy, "PHUD:CONNECT mud.example.org 23 false color=true");
+        assert!(details.use_color(&no_color));
+        let details = connect_details(Protocol::Json(1), r#"{"type":"connect","host":"mud.example.org","port":23,"tls":false,"color":false}"#);
+        assert!(!details.use_color(&color));
+    }
+
+    fn target_config() -> Config {
+        crate::test_config(&["--target-host=MUD.example.org", "--target-port=4000", "--target-tls"]).unwrap()
+    }
+
+    #[test]
+    fn connects_only_to_target() {
+        let config = target_config();
+        let target = config.target.as_ref();
+        for details in [
+            parse_connect("", target).unwrap(),
+            parse_connect("evil.example.org 23 false charset=latin1", target).unwrap(),
+            ConnectDetails::new(target, Some("evil.example.org"), Some(23), Some(false)).unwrap()
+        ] {
+            assert_eq!((details.host.as_str(), details.port, details.tls), ("MUD.example.org", 4000, true));
+        }
+        assert!(parse_connect("evil.example.org 23 false charset=latin1", target).unwrap().charset.is_some());
+    }
+
+    #[test]
+    fn allows_private_address_only_for_target() {
+        let addr = "127.0.0.1:4000".parse().unwrap();
+        assert!(check_address(&target_config(), "mud.example.org", addr).is_ok());
+        assert!(check_address(&target_config(), "evil.example.org", addr).is_err());
+        assert!(check_address(&crate::test_config(&[]).unwrap(), "mud.example.org", addr).is_err());
+    }
+
+    #[test]
+    fn sends_client_gmcp_in_both_protocols() {
+        let payload = br#"Core.Supports.Set ["Char 1","Room 1"]"#;
+        assert_eq!(client_payload(Protocol::Json(1), r#"{"type":"gmcp","package":"Core.Supports.Set","data":"[\"Char 1\",\"Room 1\"]"}"#), payload);
+        assert_eq!(client_payload(Protocol::Legacy, r#"PHUD:GMCP Core.Supports.Set ["Char 1","Room 1"]"#), payload);
+    }
+
+    #[test]
+    fn sends_client_msdp_in_both_protocols() {
+        let payload = b"\x01REPORT\x02HEALTH\x02ROOM";
+        assert_eq!(client_payload(Protocol::Json(1), r#"{"type":"msdp","command":"REPORT","args":["HEALTH","ROOM"]}"#), payload);
+        assert_eq!(client_payload(Protocol::Legacy, r#"PHUD:MSDP {"command":"REPORT","args":["HEALTH","ROOM"]}"#), payload);
+    }
+}
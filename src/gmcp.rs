@@ -0,0 +1,74 @@
+use nanoserde::SerJson;
+
+/// Telnet option number for GMCP.
+pub const GMCP: u8 = 201;
+
+#[derive(SerJson)]
+struct CoreHello {
+    client: String,
+    version: String
+}
+
+/// Sent to the MUD as soon as it agrees to speak GMCP.
+pub fn hello(client_name: &str) -> Vec<u8> {
+    let hello = CoreHello {
+        client: client_name.to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string()
+    };
+    format!("Core.Hello {}", hello.serialize_json()).into_bytes()
+}
+
+/// A GMCP message as sent to older web clients, `{"gmcp": {...}}`.
+#[derive(SerJson)]
+pub struct GmcpMessage {
+    pub gmcp: Gmcp
+}
+
+/// A single `Package.Message <json>` payload. `data` is left as a JSON
+/// string for the web client to parse, and is empty if the server sent none.
+#[derive(SerJson)]
+pub struct Gmcp {
+    pub package: String,
+    pub data: String
+}
+
+pub fn decode(buffer: &[u8]) -> Option<Gmcp> {
+    let payload = String::from_utf8_lossy(buffer);
+    let payload = payload.trim();
+    if payload.is_empty() {
+        return None;
+    }
+    let (package, data) = payload
+        .split_once(char::is_whitespace)
+        .unwrap_or((payload, ""));
+    Some(Gmcp {
+        package: package.to_string(),
+        data: data.trim().to_string()
+    })
+}
+
+/// Builds the subnegotiation payload for a message sent by the web client
+/// as `PHUD:GMCP <Package.Message> [json]`.
+pub fn encode(args: &str) -> Option<Vec<u8>> {
+    let args = args.trim();
+    let (package, data) = args
+        .split_once(' ')
+        .unwrap_or((args, ""));
+    encode_message(package, data)
+}
+
+/// Builds the subnegotiation payload for `package` with its JSON `data`,
+/// which may be empty.
+pub fn encode_message(package: &str, data: &str) -> Option<Vec<u8>> {
+    if package.is_empty() || !package.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-') {
+        return None;
+    }
+    let data = data.trim();
+    let mut payload = Vec::with_capacity(package.len() + data.len() + 1);
+    payload.extend_from_slice(package.as_bytes());
+    if !data.is_empty() {
+        payload.push(b' ');
+        payload.extend_from_slice(data.as_bytes());
+    }
+    Some(payload)
+}
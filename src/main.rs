@@ -1,6 +1,6 @@
 #![feature(ip)]
 
-use std::{env::Args, net::IpAddr};
+use std::{collections::HashMap, net::IpAddr, time::Duration};
 
 use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer, HttpResponseBuilder, http::StatusCode};
 use actix_web_actors::ws;
@@ -18,13 +18,61 @@ mod legacy;
 
 mod ansi;
 
+mod atcp;
+
+mod charset;
+
+mod gmcp;
+
+#[cfg(feature = "mccp")]
+mod mccp;
+
+mod msdp;
+
+mod mssp;
+
+mod mxp;
+
+mod policy;
+
+mod render;
+
+mod rules;
+
+mod ttype;
+
 mod config {
     use std::net::IpAddr;
+    use std::time::Duration;
+
+    use crate::policy::Policy;
+    use crate::rules::Rule;
+
+    /// The one MUD web clients are connected to, when the server is set up for it.
+    pub struct Target {
+        pub host: String,
+        pub port: u16,
+        pub tls: bool
+    }
 
     pub struct Config {
         pub ip: IpAddr,
         pub port: u16,
         pub no_color: bool,
+        pub no_mccp: bool,
+        pub client_name: String,
+        pub rules: Vec<Rule>,
+        pub policy: Policy,
+        pub target: Option<Target>,
+        /// For resolving the MUD's host and connecting to it.
+        pub connect_timeout: Duration,
+        pub tls_timeout: Duration,
+        /// How long the MUD may stay silent before the connection is dropped.
+        pub idle_timeout: Option<Duration>,
+        /// How long the MUD may stay silent before it is sent a keepalive.
+        pub keepalive: Option<Duration>,
+        /// Send telnet AYT as the keepalive, rather than NOP.
+        pub keepalive_ayt: bool,
         pub debug: bool,
         pub allow_private_connections: bool,
         pub allow_invalid_tls: bool,
@@ -43,6 +91,16 @@ mod config {
     pub fn get_config() -> &'static Config {
         unsafe { CONFIG.as_ref().unwrap() }
     }
+    /// Sets the config for tests that go through `get_config`: private
+    /// addresses allowed, so they can use local servers, and short timeouts.
+    #[cfg(test)]
+    pub fn set_test_config() {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            let config = crate::test_config(&["--allow-private-connections", "--connect-timeout=1", "--idle-timeout=1"]).unwrap();
+            unsafe { set_config(config) };
+        });
+    }
     #[macro_export]
     macro_rules! debug {
         ($($arg:tt)*) => {{
@@ -65,20 +123,58 @@ async fn echo_ws(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse,
 }
 
 async fn dyn_vars() -> Result<HttpResponse, Error> {
-    let Config { legacy_extern_port, legacy_extern_is_https, extern_is_https, legacy_extern_ip: legacy_extern_host, .. } = get_config();
+    let Config { legacy_extern_port, legacy_extern_is_https, extern_is_https, legacy_extern_ip: legacy_extern_host, target, .. } = get_config();
     let legacy_extern_port = (*legacy_extern_port).unwrap_or(443);
     
     Ok(HttpResponseBuilder::new(StatusCode::OK)
         .content_type("application/javascript")
-        .body(format!(r#"var WNG_LEGACY_CONNECTION_PORT = "{}"; var WNG_LEGACY_PREFIX = "{}"; var WNG_NORMAL_PREFIX = "{}"; var WNG_LEGACY_HOST = {};"#, 
+        .body(format!(r#"var WNG_LEGACY_CONNECTION_PORT = "{}"; var WNG_LEGACY_PREFIX = "{}"; var WNG_NORMAL_PREFIX = "{}"; var WNG_LEGACY_HOST = {}; var WNG_TARGET = {};"#, 
             legacy_extern_port, 
             if *legacy_extern_is_https { "wss" } else { "ws" },
             if *extern_is_https { "wss" } else { "ws" },
-            if let Some(leh) = legacy_extern_host { format!(r#""{}""#, leh) } else { "window.location.hostname".to_string() }
+            if let Some(leh) = legacy_extern_host { format!(r#""{}""#, leh) } else { "window.location.hostname".to_string() },
+            if let Some(Target { host, port, tls }) = target { format!(r#"{{"host": {}, "port": {}, "tls": {}}}"#, host.serialize_json(), port, tls) } else { "null".to_string() }
         )))
 }
 
-use crate::config::{Config, set_config};
+/// `GET /mssp.json?host=<host>&port=<port>[&tls=true]` - the MUD's MSSP variables as JSON.
+/// With `--target-host`, the host and port are ignored in favour of the target's.
+async fn mssp_query(query: web::Query<HashMap<String, String>>) -> Result<HttpResponse, Error> {
+    let details = (|| -> anyhow::Result<(String, u16, bool)> {
+        if let Some(Target { host, port, tls }) = &get_config().target {
+            return Ok((host.clone(), *port, *tls));
+        }
+        let host = query.get("host").context("No host provided")?.trim().to_string();
+        let port = query.get("port").context("No port provided")?.parse()?;
+        let tls = query.get("tls").map(|tls| tls.parse()).transpose()?.unwrap_or(false);
+        Ok((host, port, tls))
+    })();
+    let (host, port, tls) = match details {
+        Ok(details) => details,
+        Err(err) => return Ok(HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(MsspError { error: err.to_string() }.serialize_json()))
+    };
+    match mssp::query(&host, port, tls).await {
+        Ok(json) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json)),
+        Err(err) => {
+            debug!("MSSP query to {}:{} failed with: {}", host, port, err);
+            Ok(HttpResponse::BadGateway()
+                .content_type("application/json")
+                .body(MsspError { error: err.to_string() }.serialize_json()))
+        }
+    }
+}
+
+#[derive(SerJson)]
+struct MsspError {
+    error: String
+}
+
+use nanoserde::SerJson;
+use crate::config::{Config, Target, set_config};
 use anyhow::Context;
 
 fn flag_exists(args: &[String], flag: &str) -> bool {
@@ -104,11 +200,22 @@ fn flag_value(args: &[String], flag: &str) -> Option<String> {
     None
 }
 
-fn parse_args(args: Args) -> anyhow::Result<Config> {
+/// A flag giving a number of seconds, which must be more than 0.
+fn flag_seconds(args: &[String], flag: &str) -> anyhow::Result<Option<Duration>> {
+    match flag_value(args, flag) {
+        Some(seconds) => match seconds.parse() {
+            Ok(seconds) if seconds > 0 => Ok(Some(Duration::from_secs(seconds))),
+            _ => anyhow::bail!("{} must be a whole number of seconds, more than 0", flag)
+        },
+        None => Ok(None)
+    }
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> anyhow::Result<Config> {
     let args: Vec<String> = args.map(|x| x.trim().to_string()).collect();
     if args.len() == 1 || args.contains(&"-h".to_string()) || args.contains(&"--help".to_string()) {
         eprintln!(
-"Usage: webmud-ng <ip> <port> [--extern-is-https] [--legacy-only] [--legacy-ip=#] [--legacy-port=#] [--legacy-extern-host=#] [--legacy-extern-port=#] [--legacy-extern-is-https] [--no-color] [--serve-from=directory] [--allow-private-connections] [--allow-invalid-tls] [--debug]"
+"Usage: webmud-ng <ip> <port> [--extern-is-https] [--legacy-only] [--legacy-ip=#] [--legacy-port=#] [--legacy-extern-host=#] [--legacy-extern-port=#] [--legacy-extern-is-https] [--no-color] [--no-mccp] [--client-name=#] [--rules=file] [--policy=file] [--target-host=# --target-port=# [--target-tls]] [--connect-timeout=#] [--tls-timeout=#] [--idle-timeout=#] [--keepalive=# [--ayt-keepalive]] [--serve-from=directory] [--allow-private-connections] [--allow-invalid-tls] [--debug]"
         );
         eprintln!("See webmud-ng GitHub for details");
         std::process::exit(0);
@@ -120,6 +227,7 @@ fn parse_args(args: Args) -> anyhow::Result<Config> {
     let port: u16 = args.next().context("No port provided")?.parse()?;
     let rest: Vec<String> = args.collect();
     let no_color = flag_exists(&rest, "--no-color");
+    let no_mccp = flag_exists(&rest, "--no-mccp");
     let allow_private_connections = flag_exists(&rest, "--allow-private-connections");
     let allow_invalid_tls = flag_exists(&rest, "--allow-invalid-tls");
     let debug = flag_exists(&rest, "--debug");
@@ -141,6 +249,38 @@ fn parse_args(args: Args) -> anyhow::Result<Config> {
             .transpose()?
             .or(legacy_info.map(|(_, legacy_port)| legacy_port));
     let serve_from = flag_value(&rest, "--serve-from");
+    let client_name = flag_value(&rest, "--client-name").unwrap_or("webmud-ng".to_string());
+    if !client_name.chars().all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c)) {
+        anyhow::bail!("--client-name may only contain letters, digits, and - _ . /");
+    }
+    let rules = flag_value(&rest, "--rules").map(|path| rules::load(&path)).transpose()?.unwrap_or_default();
+    let policy = flag_value(&rest, "--policy").map(|path| policy::load(&path)).transpose()?.unwrap_or_default();
+    let target = {
+        let target_host = flag_value(&rest, "--target-host");
+        let target_port = flag_value(&rest, "--target-port");
+        let target_tls = flag_exists(&rest, "--target-tls");
+        if target_host.is_none() && target_port.is_none() && !target_tls {
+            None
+        } else if target_host.is_none() && target_port.is_none() {
+            anyhow::bail!("If --target-tls is set, a target host and port must be specified.");
+        } else if target_host.is_none() || target_port.is_none() {
+            anyhow::bail!("If a target host is specified, a target port must be specified, and vice versa.");
+        } else {
+            let host = target_host.unwrap();
+            if host.contains(char::is_whitespace) {
+                anyhow::bail!("Invalid target host");
+            }
+            Some(Target { host, port: target_port.unwrap().parse()?, tls: target_tls })
+        }
+    };
+    let connect_timeout = flag_seconds(&rest, "--connect-timeout")?.unwrap_or(Duration::from_secs(15));
+    let tls_timeout = flag_seconds(&rest, "--tls-timeout")?.unwrap_or(Duration::from_secs(15));
+    let idle_timeout = flag_seconds(&rest, "--idle-timeout")?;
+    let keepalive = flag_seconds(&rest, "--keepalive")?;
+    let keepalive_ayt = flag_exists(&rest, "--ayt-keepalive");
+    if keepalive_ayt && keepalive.is_none() {
+        anyhow::bail!("If --ayt-keepalive is set, --keepalive must be specified.");
+    }
     let legacy_extern_is_https = flag_exists(&rest, "--legacy-extern-is-https");
     let extern_is_https = flag_exists(&rest, "--extern-is-https");
     let legacy_extern_ip = flag_value(&rest, "--legacy-extern-host");
@@ -154,6 +294,16 @@ fn parse_args(args: Args) -> anyhow::Result<Config> {
         port,
         debug,
         no_color,
+        no_mccp,
+        client_name,
+        rules,
+        policy,
+        target,
+        connect_timeout,
+        tls_timeout,
+        idle_timeout,
+        keepalive,
+        keepalive_ayt,
         allow_private_connections,
         legacy_extern_is_https,
         extern_is_https,
@@ -166,6 +316,12 @@ fn parse_args(args: Args) -> anyhow::Result<Config> {
     })
 }
 
+/// Parses `flags` as if given after `webmud-ng 127.0.0.1 8080`.
+#[cfg(test)]
+fn test_config(flags: &[&str]) -> anyhow::Result<Config> {
+    parse_args(["webmud-ng", "127.0.0.1", "8080"].iter().chain(flags).map(|arg| arg.to_string()))
+}
+
 mod localip {
     use std::net::IpAddr;
 
@@ -220,7 +376,8 @@ async fn main() -> std::io::Result<()> {
             let app = App::new()
                 .service(web::resource("/").route(web::get().to(index)))
                 .service(web::resource("/ws").route(web::get().to(echo_ws)))
-                .route("/dyn_vars.js", web::get().to(dyn_vars));
+                .route("/dyn_vars.js", web::get().to(dyn_vars))
+                .route("/mssp.json", web::get().to(mssp_query));
             
             if let Some(serve_path) = serve_from.clone() {
                 app.service(actix_files::Files::new("/", &serve_path))
@@ -236,3 +393,30 @@ async fn main() -> std::io::Result<()> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shows_color_unless_no_color_is_given() {
+        assert!(!test_config(&[]).unwrap().no_color);
+        assert!(test_config(&["--no-color"]).unwrap().no_color);
+    }
+
+    #[test]
+    fn pins_target() {
+        let target = test_config(&["--target-host=mud.example.org", "--target-port=4000", "--target-tls"]).unwrap().target.unwrap();
+        assert_eq!((target.host.as_str(), target.port, target.tls), ("mud.example.org", 4000, true));
+        assert!(!test_config(&["--target-host=mud.example.org", "--target-port=4000"]).unwrap().target.unwrap().tls);
+        assert!(test_config(&[]).unwrap().target.is_none());
+    }
+
+    #[test]
+    fn needs_target_for_target_tls() {
+        let err = test_config(&["--target-tls"]).err().unwrap();
+        assert_eq!(err.to_string(), "If --target-tls is set, a target host and port must be specified.");
+        assert!(test_config(&["--target-host=mud.example.org"]).is_err());
+        assert!(test_config(&["--target-port=4000", "--target-tls"]).is_err());
+    }
+}
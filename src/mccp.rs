@@ -0,0 +1,202 @@
+use anyhow::Result;
+use flate2::{Compress, Compression as Level, Decompress, FlushCompress, FlushDecompress, Status};
+use libtelnet_rs::Parser;
+use libtelnet_rs::compatibility::CompatibilityTable;
+use libtelnet_rs::events::{TelnetEvents, TelnetSubnegotiation};
+
+use crate::conn::support_server_option;
+
+/// Telnet option numbers for server-to-client and client-to-server compression.
+pub const MCCP2: u8 = 86;
+pub const MCCP3: u8 = 87;
+
+/// Offers compression in both directions on the connection to the MUD.
+pub fn support(options: &mut CompatibilityTable) {
+    // The zlib stream starts right after `IAC SB MCCP2 IAC SE`, often in the same read.
+    support_server_option(options, MCCP2);
+    options.support_remote(MCCP3);
+}
+
+/// zlib streams negotiated on the MUD socket.
+/// `inflate` is active between `IAC SB MCCP2 IAC SE` and the end of the
+/// server's zlib stream, `deflate` from the moment we start MCCP3.
+pub struct Compression {
+    inflate: Option<Decompress>,
+    deflate: Option<Compress>
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self { inflate: None, deflate: None }
+    }
+
+    /// Runs bytes read from the MUD through the decompressor (while MCCP2 is
+    /// active) and the telnet parser. Compressed data that arrives in the same
+    /// read as the start of the stream, and uncompressed data following the
+    /// end of it, are both handled here.
+    pub fn receive(&mut self, telnet: &mut Parser, data: &[u8]) -> Result<Vec<TelnetEvents>> {
+        let mut events = Vec::new();
+        let mut chunk = data.to_vec();
+        while !chunk.is_empty() {
+            let (plain, mut rest) = match &mut self.inflate {
+                Some(inflate) => {
+                    let mut out = Vec::with_capacity(chunk.len() * 4);
+                    match decompress(inflate, &chunk, &mut out)? {
+                        Some(trailing) => {
+                            self.inflate = None;
+                            (out, trailing)
+                        },
+                        None => (out, Vec::new())
+                    }
+                },
+                None => (std::mem::take(&mut chunk), Vec::new())
+            };
+            for event in telnet.receive(&plain) {
+                match event {
+                    TelnetEvents::DecompressImmediate(compressed) => {
+                        // Everything after `IAC SE` in this chunk belongs to the zlib stream.
+                        self.inflate.get_or_insert_with(|| Decompress::new(true));
+                        let mut next = compressed.to_vec();
+                        next.extend_from_slice(&rest);
+                        rest = next;
+                    },
+                    TelnetEvents::Subnegotiation(TelnetSubnegotiation { option: MCCP2, .. }) => {
+                        self.inflate.get_or_insert_with(|| Decompress::new(true));
+                    },
+                    event => events.push(event)
+                }
+            }
+            chunk = rest;
+        }
+        Ok(events)
+    }
+
+    /// Switches on compression of everything we send from now on. The caller
+    /// must already have sent `IAC SB MCCP3 IAC SE` uncompressed.
+    pub fn start_deflate(&mut self) {
+        self.deflate.get_or_insert_with(|| Compress::new(Level::default(), true));
+    }
+
+    pub fn deflating(&self) -> bool {
+        self.deflate.is_some()
+    }
+
+    /// Compresses `data` if MCCP3 is active, flushing so the MUD sees it immediately.
+    pub fn outgoing(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        let deflate = match &mut self.deflate {
+            Some(deflate) => deflate,
+            None => return Ok(None)
+        };
+        let mut out = Vec::with_capacity(data.len() + 64);
+        let mut input = data;
+        loop {
+            out.reserve(input.len() + 64);
+            let before = deflate.total_in();
+            deflate.compress_vec(input, &mut out, FlushCompress::Sync)?;
+            input = &input[(deflate.total_in() - before) as usize..];
+            if input.is_empty() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        Ok(Some(out))
+    }
+}
+
+/// Inflates all of `input` into `out`. If the zlib stream ends, returns the
+/// bytes that followed it, which are no longer compressed.
+fn decompress(inflate: &mut Decompress, input: &[u8], out: &mut Vec<u8>) -> Result<Option<Vec<u8>>> {
+    let mut input = input;
+    loop {
+        out.reserve(4096.max(input.len() * 2));
+        let before = inflate.total_in();
+        let status = inflate.decompress_vec(input, out, FlushDecompress::None)?;
+        input = &input[(inflate.total_in() - before) as usize..];
+        if status == Status::StreamEnd {
+            return Ok(Some(input.to_vec()));
+        }
+        if status == Status::BufError || (input.is_empty() && out.len() < out.capacity()) {
+            return Ok(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::ZlibEncoder;
+    use libtelnet_rs::telnet::op_command::{IAC, SB, SE, WILL, DO};
+
+    use super::*;
+
+    fn parser() -> Parser {
+        let mut options = CompatibilityTable::new();
+        support(&mut options);
+        Parser::with_support(options)
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// The text and the replies in `events`.
+    fn split(events: Vec<TelnetEvents>) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let mut text = Vec::new();
+        let mut sent = Vec::new();
+        for event in events {
+            match event {
+                TelnetEvents::DataReceive(data) => text.extend_from_slice(&data),
+                TelnetEvents::DataSend(data) => sent.push(data.to_vec()),
+                _ => {}
+            }
+        }
+        (text, sent)
+    }
+
+    /// `WILL MCCP2`, the start of the stream, a compressed greeting, and text
+    /// after the end of the stream.
+    fn session() -> (Vec<u8>, usize) {
+        let mut data = vec![IAC, WILL, MCCP2, IAC, SB, MCCP2, IAC, SE];
+        let header = data.len();
+        data.extend(zlib(b"Welcome!\r\nName: "));
+        data.extend_from_slice(b"plain\r\n");
+        (data, header)
+    }
+
+    #[test]
+    fn inflates_stream_started_in_the_same_read() {
+        let (data, _) = session();
+        let events = Compression::new().receive(&mut parser(), &data).unwrap();
+        let (text, sent) = split(events);
+        assert_eq!(sent, vec![vec![IAC, DO, MCCP2]]);
+        assert_eq!(text, b"Welcome!\r\nName: plain\r\n");
+    }
+
+    #[test]
+    fn inflates_stream_split_across_reads() {
+        let (data, header) = session();
+        for at in header..=data.len() {
+            let mut telnet = parser();
+            let mut mccp = Compression::new();
+            let mut events = mccp.receive(&mut telnet, &data[..at]).unwrap();
+            events.extend(mccp.receive(&mut telnet, &data[at..]).unwrap());
+            assert_eq!(split(events).0, b"Welcome!\r\nName: plain\r\n", "split at {}", at);
+        }
+    }
+
+    #[test]
+    fn deflates_outgoing_data() {
+        let mut mccp = Compression::new();
+        assert!(mccp.outgoing(b"look\r\n").unwrap().is_none());
+        mccp.start_deflate();
+        assert!(mccp.deflating());
+        let mut inflate = Decompress::new(true);
+        let mut out = Vec::new();
+        for line in [&b"look\r\n"[..], b"say hi\r\n"] {
+            decompress(&mut inflate, &mccp.outgoing(line).unwrap().unwrap(), &mut out).unwrap();
+        }
+        assert_eq!(out, b"look\r\nsay hi\r\n");
+    }
+}
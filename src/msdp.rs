@@ -0,0 +1,299 @@
+use nanoserde::{DeJson, SerJson};
+
+/// Telnet option number for MSDP.
+pub const MSDP: u8 = 69;
+
+const VAR: u8 = 1;
+const VAL: u8 = 2;
+const TABLE_OPEN: u8 = 3;
+const TABLE_CLOSE: u8 = 4;
+const ARRAY_OPEN: u8 = 5;
+const ARRAY_CLOSE: u8 = 6;
+
+/// How deeply tables and arrays may nest. Anything nested deeper is skipped,
+/// so a hostile MUD can't exhaust the stack.
+const MAX_DEPTH: usize = 32;
+
+/// Commands a client may send to the server, see the MSDP specification.
+static COMMANDS: [&'static str; 5] = ["LIST", "REPORT", "RESET", "SEND", "UNREPORT"];
+
+/// Sent to the MUD as soon as it agrees to speak MSDP.
+pub fn hello(client_name: &str) -> Vec<u8> {
+    encode_vars(&[
+        ("CLIENT_ID", &[client_name]),
+        ("CLIENT_VERSION", &[env!("CARGO_PKG_VERSION")])
+    ])
+}
+
+pub enum MsdpValue {
+    String(String),
+    Array(Vec<MsdpValue>),
+    Table(Vec<(String, MsdpValue)>)
+}
+
+impl MsdpValue {
+    pub fn to_json(&self, out: &mut String) {
+        match self {
+            MsdpValue::String(s) => out.push_str(&s.serialize_json()),
+            MsdpValue::Array(values) => {
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i != 0 {
+                        out.push(',');
+                    }
+                    value.to_json(out);
+                }
+                out.push(']');
+            },
+            MsdpValue::Table(entries) => {
+                out.push('{');
+                for (i, (name, value)) in entries.iter().enumerate() {
+                    if i != 0 {
+                        out.push(',');
+                    }
+                    out.push_str(&name.serialize_json());
+                    out.push(':');
+                    value.to_json(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+}
+
+/// Parses a whole subnegotiation buffer into a table of variables.
+/// Malformed input is tolerated: anything that can't be attributed to a
+/// variable is skipped.
+pub fn parse(buffer: &[u8]) -> MsdpValue {
+    let mut pos = 0;
+    MsdpValue::Table(parse_table(buffer, &mut pos, None, 0))
+}
+
+/// Formats the variables in `buffer` as `{"msdp": {...}}` for the web client.
+pub fn to_client_json(buffer: &[u8]) -> String {
+    let mut out = String::with_capacity(buffer.len() * 2 + 10);
+    out.push_str(r#"{"msdp":"#);
+    parse(buffer).to_json(&mut out);
+    out.push('}');
+    out
+}
+
+fn read_string(buffer: &[u8], pos: &mut usize) -> String {
+    let start = *pos;
+    while *pos < buffer.len() && !(VAR..=ARRAY_CLOSE).contains(&buffer[*pos]) {
+        *pos += 1;
+    }
+    String::from_utf8_lossy(&buffer[start..*pos]).to_string()
+}
+
+fn parse_table(buffer: &[u8], pos: &mut usize, close: Option<u8>, depth: usize) -> Vec<(String, MsdpValue)> {
+    let mut entries = Vec::new();
+    while *pos < buffer.len() {
+        let byte = buffer[*pos];
+        if Some(byte) == close {
+            *pos += 1;
+            break;
+        }
+        if byte != VAR {
+            *pos += 1;
+            continue;
+        }
+        *pos += 1;
+        let name = read_string(buffer, pos);
+        let mut values = Vec::new();
+        while *pos < buffer.len() && buffer[*pos] == VAL {
+            *pos += 1;
+            values.push(parse_value(buffer, pos, depth));
+        }
+        let value = match values.len() {
+            0 => MsdpValue::String(String::new()),
+            1 => values.pop().unwrap(),
+            _ => MsdpValue::Array(values)
+        };
+        if !name.is_empty() {
+            entries.push((name, value));
+        }
+    }
+    entries
+}
+
+fn parse_array(buffer: &[u8], pos: &mut usize, depth: usize) -> Vec<MsdpValue> {
+    let mut values = Vec::new();
+    while *pos < buffer.len() {
+        match buffer[*pos] {
+            ARRAY_CLOSE => {
+                *pos += 1;
+                break;
+            },
+            VAL => {
+                *pos += 1;
+                values.push(parse_value(buffer, pos, depth));
+            },
+            _ => *pos += 1
+        }
+    }
+    values
+}
+
+/// Parses a value nested in `depth` tables and arrays below the top level.
+fn parse_value(buffer: &[u8], pos: &mut usize, depth: usize) -> MsdpValue {
+    match buffer.get(*pos) {
+        Some(&TABLE_OPEN | &ARRAY_OPEN) if depth >= MAX_DEPTH => {
+            skip_nested(buffer, pos);
+            MsdpValue::String(String::new())
+        },
+        Some(&TABLE_OPEN) => {
+            *pos += 1;
+            MsdpValue::Table(parse_table(buffer, pos, Some(TABLE_CLOSE), depth + 1))
+        },
+        Some(&ARRAY_OPEN) => {
+            *pos += 1;
+            MsdpValue::Array(parse_array(buffer, pos, depth + 1))
+        },
+        _ => MsdpValue::String(read_string(buffer, pos))
+    }
+}
+
+/// Skips the table or array starting at `pos`, along with everything nested in it.
+fn skip_nested(buffer: &[u8], pos: &mut usize) {
+    let mut open = 0;
+    while *pos < buffer.len() {
+        let byte = buffer[*pos];
+        *pos += 1;
+        match byte {
+            TABLE_OPEN | ARRAY_OPEN => open += 1,
+            TABLE_CLOSE | ARRAY_CLOSE => {
+                open -= 1;
+                if open == 0 {
+                    break;
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+fn encode_vars(vars: &[(&str, &[&str])]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (name, values) in vars {
+        out.push(VAR);
+        out.extend_from_slice(name.as_bytes());
+        for value in values.iter() {
+            out.push(VAL);
+            out.extend_from_slice(value.as_bytes());
+        }
+    }
+    out
+}
+
+/// A command from the web client, sent as
+/// `PHUD:MSDP {"command": "REPORT", "args": ["HEALTH", "MANA"]}`.
+#[derive(DeJson)]
+struct ClientCommand {
+    command: String,
+    args: Vec<String>
+}
+
+/// Builds the subnegotiation payload for a command sent by the web client.
+pub fn encode(json: &str) -> Option<Vec<u8>> {
+    let cmd = ClientCommand::deserialize_json(json.trim()).ok()?;
+    encode_command(&cmd.command, &cmd.args)
+}
+
+/// Builds the subnegotiation payload for `command` with its arguments,
+/// or `None` if the command is unknown or an argument can't be sent.
+pub fn encode_command(command: &str, args: &[String]) -> Option<Vec<u8>> {
+    let command = command.to_ascii_uppercase();
+    if !COMMANDS.contains(&command.as_str()) || args.is_empty() {
+        return None;
+    }
+    let valid = |s: &String| !s.is_empty() && !s.bytes().any(|b| (VAR..=ARRAY_CLOSE).contains(&b));
+    if !args.iter().all(valid) {
+        return None;
+    }
+    let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+    Some(encode_vars(&[(&command, &args)]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(buffer: &[u8]) -> String {
+        let mut out = String::new();
+        parse(buffer).to_json(&mut out);
+        out
+    }
+
+    #[test]
+    fn parses_variables() {
+        assert_eq!(json(b"\x01HEALTH\x02100\x01MANA\x0250"), r#"{"HEALTH":"100","MANA":"50"}"#);
+        assert_eq!(json(b"\x01NAME\x02"), r#"{"NAME":""}"#);
+        assert_eq!(json(b"\x01NAME"), r#"{"NAME":""}"#);
+    }
+
+    #[test]
+    fn parses_repeated_values_as_an_array() {
+        assert_eq!(json(b"\x01AFFECTS\x02blind\x02deaf"), r#"{"AFFECTS":["blind","deaf"]}"#);
+    }
+
+    #[test]
+    fn parses_arrays() {
+        assert_eq!(
+            json(b"\x01REPORTABLE_VARIABLES\x02\x05\x02HEALTH\x02MANA\x06\x01NEXT\x021"),
+            r#"{"REPORTABLE_VARIABLES":["HEALTH","MANA"],"NEXT":"1"}"#
+        );
+    }
+
+    #[test]
+    fn parses_nested_tables() {
+        assert_eq!(
+            json(b"\x01ROOM\x02\x03\x01VNUM\x026008\x01EXITS\x02\x03\x01n\x026011\x01e\x026007\x04\x04\x01HEALTH\x021"),
+            r#"{"ROOM":{"VNUM":"6008","EXITS":{"n":"6011","e":"6007"}},"HEALTH":"1"}"#
+        );
+        assert_eq!(
+            json(b"\x01GROUP\x02\x05\x02\x03\x01NAME\x02Bob\x04\x02\x03\x01NAME\x02Ann\x04\x06"),
+            r#"{"GROUP":[{"NAME":"Bob"},{"NAME":"Ann"}]}"#
+        );
+    }
+
+    #[test]
+    fn tolerates_malformed_input() {
+        // Bytes before the first VAR, and a table and an array that are never closed.
+        assert_eq!(json(b"junk\x02x\x01HEALTH\x02100"), r#"{"HEALTH":"100"}"#);
+        assert_eq!(json(b"\x01ROOM\x02\x03\x01VNUM\x026008"), r#"{"ROOM":{"VNUM":"6008"}}"#);
+        assert_eq!(json(b"\x01LIST\x02\x05\x02a\x02b"), r#"{"LIST":["a","b"]}"#);
+        assert_eq!(json(b""), "{}");
+    }
+
+    #[test]
+    fn skips_values_nested_too_deeply() {
+        let tables = |levels: usize| {
+            let mut buffer = b"\x01A\x02".to_vec();
+            for _ in 0..levels {
+                buffer.extend_from_slice(b"\x03\x01A\x02");
+            }
+            buffer.push(b'x');
+            buffer.extend(std::iter::repeat(TABLE_CLOSE).take(levels));
+            buffer.extend_from_slice(b"\x01B\x02after");
+            buffer
+        };
+        let expected = |inner: &str| format!(r#"{}{}{},"B":"after"}}"#, r#"{"A":"#.repeat(MAX_DEPTH + 1), inner, "}".repeat(MAX_DEPTH));
+        assert_eq!(json(&tables(MAX_DEPTH)), expected(r#""x""#));
+        assert_eq!(json(&tables(100_000)), expected(r#""""#));
+        assert_eq!(json(&b"\x01A\x02\x05\x02".repeat(100_000)).matches('[').count(), MAX_DEPTH);
+    }
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(json(b"\x01SAY\x02\"hi\" \\o/"), r#"{"SAY":"\"hi\" \\o/"}"#);
+    }
+
+    #[test]
+    fn encodes_client_commands() {
+        assert_eq!(encode(r#"{"command": "report", "args": ["HEALTH", "MANA"]}"#).unwrap(), b"\x01REPORT\x02HEALTH\x02MANA");
+        assert_eq!(encode(r#"{"command": "DELETE", "args": ["HEALTH"]}"#), None);
+        assert_eq!(encode(r#"{"command": "REPORT", "args": []}"#), None);
+        assert_eq!(encode_command("SEND", &["A\x01B".to_string()]), None);
+    }
+}
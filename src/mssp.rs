@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, Context, bail};
+use lazy_static::lazy_static;
+use libtelnet_rs::Parser;
+use libtelnet_rs::compatibility::CompatibilityTable;
+use libtelnet_rs::events::{TelnetEvents, TelnetSubnegotiation};
+use tokio::sync::Semaphore;
+
+use crate::conn::{self, MaybeTls};
+use crate::debug;
+use crate::msdp;
+
+/// Telnet option number for MSSP.
+pub const MSSP: u8 = 70;
+
+/// How long to wait for a server to send its MSSP variables.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a result is reused before the server is asked again.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// How long a failed query is remembered, so a host that doesn't answer
+/// isn't dialled again for every request.
+const FAILURE_TTL: Duration = Duration::from_secs(30);
+
+/// Most results kept at once. When full, the one closest to expiring goes.
+const MAX_CACHED: usize = 1024;
+
+/// Most queries in progress at once. Requests beyond that are turned away.
+const MAX_QUERIES: usize = 8;
+
+lazy_static! {
+    /// Results by host, port and TLS, with when they expire. Failures are
+    /// kept as their error message.
+    static ref CACHE: Mutex<HashMap<(String, u16, bool), (Instant, Result<String, String>)>> = Mutex::new(HashMap::new());
+    static ref QUERIES: Semaphore = Semaphore::new(MAX_QUERIES);
+}
+
+/// Connects to the MUD, waits for it to offer MSSP, and returns its variables
+/// as a JSON object, e.g. `{"NAME":"...","PLAYERS":"12","UPTIME":"..."}`.
+/// Variables with several values become arrays.
+pub async fn query(host: &str, port: u16, tls: bool) -> Result<String> {
+    let key = (host.to_ascii_lowercase(), port, tls);
+    if let Some((expires, result)) = CACHE.lock().unwrap().get(&key) {
+        if *expires > Instant::now() {
+            return result.clone().map_err(|err| anyhow::anyhow!(err));
+        }
+    }
+
+    let _permit = QUERIES.try_acquire().ok().context("Too many MSSP queries in progress, try again later")?;
+    let result = match tokio::time::timeout(QUERY_TIMEOUT, Box::pin(fetch(host, port, tls))).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow::anyhow!("Timed out waiting for MSSP"))
+    };
+
+    let now = Instant::now();
+    let ttl = if result.is_ok() { CACHE_TTL } else { FAILURE_TTL };
+    let mut cache = CACHE.lock().unwrap();
+    cache.retain(|_, (expires, _)| *expires > now);
+    if cache.len() >= MAX_CACHED {
+        let soonest = cache.iter().min_by_key(|(_, (expires, _))| *expires).map(|(key, _)| key.clone());
+        if let Some(soonest) = soonest {
+            cache.remove(&soonest);
+        }
+    }
+    cache.insert(key, (now + ttl, result.as_ref().cloned().map_err(|err| err.to_string())));
+    result
+}
+
+async fn fetch(host: &str, port: u16, tls: bool) -> Result<String> {
+    let mut conn = MaybeTls::connect(host, port, tls).await?;
+    debug!("Querying MSSP from {}:{}", host, port);
+
+    let mut options = CompatibilityTable::new();
+    conn::support_server_option(&mut options, MSSP);
+    let mut telnet = Parser::with_support(options);
+    let mut buf = vec![0; 2048];
+
+    loop {
+        let bytes_read = conn.read(&mut buf).await?;
+        if bytes_read == 0 {
+            bail!("Connection closed before the server sent MSSP");
+        }
+        let (replies, json) = receive(&mut telnet, &buf[..bytes_read]);
+        for to_send in replies {
+            conn.write_all(&to_send).await?;
+        }
+        if let Some(json) = json {
+            return Ok(json);
+        }
+    }
+}
+
+/// Parses one read from the server. Returns the negotiation replies to send
+/// and, once the server has sent them, its variables as JSON.
+fn receive(telnet: &mut Parser, data: &[u8]) -> (Vec<Vec<u8>>, Option<String>) {
+    let mut replies = Vec::new();
+    for event in telnet.receive(data) {
+        match event {
+            TelnetEvents::DataSend(to_send) => replies.push(to_send.to_vec()),
+            TelnetEvents::Subnegotiation(TelnetSubnegotiation { option: MSSP, buffer }) => {
+                // MSSP uses the same VAR/VAL bytes as MSDP, without tables or arrays.
+                let mut json = String::with_capacity(buffer.len() * 2);
+                msdp::parse(&buffer).to_json(&mut json);
+                return (replies, Some(json));
+            },
+            _ => {}
+        }
+    }
+    (replies, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use libtelnet_rs::telnet::op_command::{IAC, SB, SE, WILL, DO};
+
+    use super::*;
+
+    const VAR: u8 = 1;
+    const VAL: u8 = 2;
+
+    fn parser() -> Parser {
+        let mut options = CompatibilityTable::new();
+        conn::support_server_option(&mut options, MSSP);
+        Parser::with_support(options)
+    }
+
+    fn variables() -> Vec<u8> {
+        let mut data = vec![IAC, SB, MSSP];
+        for (name, values) in [("NAME", &["Example MUD"][..]), ("PLAYERS", &["12"]), ("PORT", &["4000", "4443"])] {
+            data.push(VAR);
+            data.extend_from_slice(name.as_bytes());
+            for value in values {
+                data.push(VAL);
+                data.extend_from_slice(value.as_bytes());
+            }
+        }
+        data.extend_from_slice(&[IAC, SE]);
+        data
+    }
+
+    const JSON: &str = r#"{"NAME":"Example MUD","PLAYERS":"12","PORT":["4000","4443"]}"#;
+
+    #[test]
+    fn returns_variables_sent_with_the_offer() {
+        let mut data = vec![IAC, WILL, MSSP];
+        data.extend(variables());
+        let (replies, json) = receive(&mut parser(), &data);
+        assert_eq!(replies, vec![vec![IAC, DO, MSSP]]);
+        assert_eq!(json.as_deref(), Some(JSON));
+    }
+
+    #[test]
+    fn returns_variables_sent_after_the_offer() {
+        let mut telnet = parser();
+        let (replies, json) = receive(&mut telnet, b"Welcome!\r\n\xFF\xFB\x46");
+        assert_eq!(replies, vec![vec![IAC, DO, MSSP]]);
+        assert_eq!(json, None);
+        let (replies, json) = receive(&mut telnet, &variables());
+        assert!(replies.is_empty());
+        assert_eq!(json.as_deref(), Some(JSON));
+    }
+}
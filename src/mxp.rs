@@ -0,0 +1,607 @@
+use std::collections::HashMap;
+
+use crate::ansi::{Color, Link, Styles, Token};
+
+/// Telnet option number for MXP.
+pub const MXP: u8 = 91;
+
+/// Longest tag or entity held back while waiting for the rest of it.
+const MAX_PENDING: usize = 1024;
+
+/// Longest command a `<send>` link may carry.
+const MAX_COMMAND: usize = 256;
+
+/// How deeply custom elements may expand into other custom elements.
+const MAX_EXPANSION: usize = 4;
+
+/// Most tags left open, or custom elements defined, at once.
+const MAX_TAGS: usize = 256;
+
+/// Color names MXP servers commonly use, beyond `#rrggbb`.
+static COLOR_NAMES: [(&'static str, (u8, u8, u8)); 19] = [
+    ("black", (0, 0, 0)), ("red", (255, 0, 0)), ("green", (0, 128, 0)), ("yellow", (255, 255, 0)),
+    ("blue", (0, 0, 255)), ("magenta", (255, 0, 255)), ("cyan", (0, 255, 255)), ("white", (255, 255, 255)),
+    ("gray", (128, 128, 128)), ("grey", (128, 128, 128)), ("silver", (192, 192, 192)), ("maroon", (128, 0, 0)),
+    ("olive", (128, 128, 0)), ("navy", (0, 0, 128)), ("purple", (128, 0, 128)), ("teal", (0, 128, 128)),
+    ("lime", (0, 255, 0)), ("fuchsia", (255, 0, 255)), ("orange", (255, 165, 0))
+];
+
+fn parse_color(value: &str) -> Option<Color> {
+    let value = value.trim().to_ascii_lowercase();
+    if let Some(hex) = value.strip_prefix('#') {
+        let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+        return match hex.len() {
+            6 => Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?)),
+            _ => None
+        };
+    }
+    COLOR_NAMES.iter()
+        .find(|(name, _)| *name == value)
+        .map(|&(_, (r, g, b))| Color::Rgb(r, g, b))
+}
+
+/// A command from `<send>`, if it is safe to let the user send with a click.
+/// Only the first of several `|`-separated commands is used.
+fn parse_command(href: &str) -> Option<String> {
+    let command = href.split('|').next()?.trim();
+    if command.is_empty() || command.len() > MAX_COMMAND || command.starts_with("PHUD:") || command.chars().any(char::is_control) {
+        return None;
+    }
+    Some(command.to_string())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    /// Only formatting tags are allowed.
+    Open,
+    /// All supported tags are allowed.
+    Secure,
+    /// Nothing is parsed.
+    Locked
+}
+
+/// What an open tag does to the text inside it.
+#[derive(Clone)]
+enum Effect {
+    Bold,
+    Italic,
+    Underline,
+    Strikeout,
+    Fore(Color),
+    Back(Color),
+    Link(Link),
+    /// A `<send>` whose command depends on the text inside it, with `&text;`
+    /// standing in for that text.
+    SendText(String)
+}
+
+struct OpenTag {
+    name: String,
+    effects: Vec<Effect>,
+    /// Tags opened in open mode are closed at the end of the line.
+    open_mode: bool
+}
+
+/// A custom element defined with `<!ELEMENT>`.
+struct Element {
+    /// The tags the element stands for, e.g. `<send href='&text;'>`.
+    definition: String,
+    /// Attribute names with their default values, in positional order.
+    attributes: Vec<(String, String)>,
+    /// `OPEN`: may be used in open mode.
+    open: bool,
+    /// `EMPTY`: has no closing tag.
+    empty: bool
+}
+
+/// Text inside a `<send>` that uses its text as the command, held back
+/// until the closing tag.
+struct Capture {
+    template: String,
+    pieces: Vec<(Styles, String)>,
+    /// Length of the text in `pieces`.
+    len: usize
+}
+
+/// Splits `<tag attr=value "positional">` contents into the tag name and
+/// its attributes, with names lowercased. Positional attributes have no name.
+fn parse_tag(tag: &str) -> (String, Vec<(Option<String>, String)>) {
+    let mut words = Vec::new();
+    let mut chars = tag.trim().chars().peekable();
+    while chars.peek().is_some() {
+        let mut word = String::new();
+        let mut name = None;
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                break;
+            }
+            chars.next();
+            match c {
+                '\'' | '"' => {
+                    for quoted in chars.by_ref() {
+                        if quoted == c {
+                            break;
+                        }
+                        word.push(quoted);
+                    }
+                },
+                '=' if name.is_none() => name = Some(std::mem::take(&mut word).to_ascii_lowercase()),
+                c => word.push(c)
+            }
+        }
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        words.push((name, word));
+    }
+    let mut words = words.into_iter();
+    let name = words.next().map(|(_, name)| name.to_ascii_lowercase()).unwrap_or_default();
+    (name, words.collect())
+}
+
+/// Looks up an attribute by name, or else by its position among the unnamed ones.
+fn attribute<'a>(attributes: &'a [(Option<String>, String)], name: &str, position: usize) -> Option<&'a str> {
+    attributes.iter()
+        .find(|(key, _)| key.as_deref() == Some(name))
+        .or_else(|| attributes.iter().filter(|(key, _)| key.is_none()).nth(position))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Finds the `>` that ends the tag starting at `str`, skipping over quoted values.
+fn tag_end(str: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in str.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '>') => return Some(i),
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (_, '\n') => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Decodes the entity at the start of `str`, returning its length and text.
+fn entity(str: &str) -> Option<(usize, String)> {
+    let end = str.find(';')?;
+    let name = &str[1..end];
+    let decoded = match name {
+        "lt" => '<',
+        "gt" => '>',
+        "amp" => '&',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{A0}',
+        _ => {
+            let code = name.strip_prefix("#x").or(name.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| name.strip_prefix('#').and_then(|dec| dec.parse().ok()))?;
+            char::from_u32(code).filter(|c| !c.is_control())?
+        }
+    };
+    Some((end + 1, decoded.to_string()))
+}
+
+/// Parses MXP tags out of the MUD's text and applies the ones in its safe
+/// subset: `<b>`, `<i>`, `<u>`, `<s>`, `<color>`/`<font>`, `<br>`, and in
+/// secure mode `<a>`, `<send>` and `<!ELEMENT>`. Everything else is left in
+/// the text, so it is escaped like any other output.
+pub struct Mxp {
+    default_mode: Mode,
+    /// Mode set by `ESC[0z`..`ESC[2z`, until the end of the line.
+    line_mode: Option<Mode>,
+    /// `ESC[4z`: the next tag may be a secure one.
+    temp_secure: bool,
+    stack: Vec<OpenTag>,
+    elements: HashMap<String, Element>,
+    capture: Option<Capture>,
+    /// A tag or entity cut off at the end of the last text.
+    pending: String,
+    color: bool
+}
+
+impl Mxp {
+    pub fn new(color: bool) -> Self {
+        Self {
+            default_mode: Mode::Open,
+            line_mode: None,
+            temp_secure: false,
+            stack: Vec::new(),
+            elements: HashMap::new(),
+            capture: None,
+            pending: String::new(),
+            color
+        }
+    }
+
+    fn mode(&self) -> Mode {
+        if self.temp_secure {
+            Mode::Secure
+        } else {
+            self.line_mode.unwrap_or(self.default_mode)
+        }
+    }
+
+    /// Handles `ESC[<mode>z`.
+    pub fn set_mode(&mut self, mode: u32, token: &mut impl FnMut(Token)) {
+        match mode {
+            0 => self.line_mode = Some(Mode::Open),
+            1 => self.line_mode = Some(Mode::Secure),
+            2 => self.line_mode = Some(Mode::Locked),
+            3 => {
+                self.finish_capture(token);
+                self.stack.clear();
+                self.line_mode = None;
+                self.default_mode = Mode::Open;
+            },
+            4 => self.temp_secure = true,
+            5 => self.default_mode = Mode::Open,
+            6 => self.default_mode = Mode::Secure,
+            7 => self.default_mode = Mode::Locked,
+            _ => {}
+        }
+        if mode >= 5 {
+            self.line_mode = None;
+        }
+    }
+
+    /// Parses a run of text in the given ANSI style, calling `token` for the
+    /// text left once tags and entities are taken out.
+    pub fn text(&mut self, text: &str, styles: &Styles, link: Option<&Link>, token: &mut impl FnMut(Token)) {
+        let input = if self.pending.is_empty() {
+            text.to_string()
+        } else {
+            let mut input = std::mem::take(&mut self.pending);
+            input.push_str(text);
+            input
+        };
+
+        let mut rest = input.as_str();
+        while !rest.is_empty() {
+            let special = if self.mode() == Mode::Locked {
+                rest.find('\n')
+            } else {
+                rest.find(|c| c == '<' || c == '&' || c == '\n')
+            };
+            let i = match special {
+                Some(i) => i,
+                None => {
+                    self.emit(rest, styles, link, token);
+                    break;
+                }
+            };
+            self.emit(&rest[..i], styles, link, token);
+            rest = &rest[i..];
+            match rest.as_bytes()[0] {
+                b'\n' => {
+                    self.emit("\n", styles, link, token);
+                    self.end_line(token);
+                    rest = &rest[1..];
+                },
+                b'&' => match entity(rest) {
+                    Some((len, decoded)) => {
+                        self.emit(&decoded, styles, link, token);
+                        rest = &rest[len..];
+                    },
+                    None if rest.len() < 12 && rest[1..].chars().all(|c| c.is_ascii_alphanumeric() || c == '#') => {
+                        self.pending = rest.to_string();
+                        break;
+                    },
+                    None => {
+                        self.emit("&", styles, link, token);
+                        rest = &rest[1..];
+                    }
+                },
+                _ => match tag_end(rest) {
+                    Some(end) => {
+                        if !self.tag(&rest[1..end], token) {
+                            self.emit(&rest[..=end], styles, link, token);
+                        }
+                        self.temp_secure = false;
+                        rest = &rest[end + 1..];
+                    },
+                    None if rest.len() < MAX_PENDING && !rest.contains('\n')
+                        && rest[1..].chars().next().map_or(true, |c| c.is_ascii_alphabetic() || c == '/' || c == '!') => {
+                        self.pending = rest.to_string();
+                        break;
+                    },
+                    None => {
+                        self.emit("<", styles, link, token);
+                        rest = &rest[1..];
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends text on, with the style and link of the open tags added to it.
+    fn emit(&mut self, text: &str, styles: &Styles, link: Option<&Link>, token: &mut impl FnMut(Token)) {
+        if text.is_empty() {
+            return;
+        }
+        let mut styles = *styles;
+        let mut link = link.cloned();
+        for effect in self.stack.iter().flat_map(|open| open.effects.iter()) {
+            match effect {
+                Effect::Bold => styles.bold = true,
+                Effect::Italic => styles.italic = true,
+                Effect::Underline => styles.underline = true,
+                Effect::Strikeout => styles.strikethrough = true,
+                Effect::Fore(color) if self.color => styles.color = Some(*color),
+                Effect::Back(color) if self.color => styles.background = Some(*color),
+                Effect::Link(tag_link) => link = Some(tag_link.clone()),
+                _ => {}
+            }
+        }
+        match &mut self.capture {
+            Some(capture) => {
+                capture.len += text.len();
+                capture.pieces.push((styles, text.to_string()));
+            },
+            None => token(Token::Text(&styles, link.as_ref(), text))
+        }
+        // Too long to be a command, so it is sent on unlinked rather than held back.
+        if self.capture.as_ref().map_or(false, |capture| capture.len > MAX_COMMAND) {
+            for (styles, text) in self.capture.take().into_iter().flat_map(|capture| capture.pieces) {
+                token(Token::Text(&styles, None, &text));
+            }
+        }
+    }
+
+    fn end_line(&mut self, token: &mut impl FnMut(Token)) {
+        self.finish_capture(token);
+        if self.mode() == Mode::Open {
+            self.stack.retain(|open| !open.open_mode);
+        }
+        self.line_mode = None;
+        self.temp_secure = false;
+    }
+
+    /// Sends the text held back for a `<send>`, linked to the command it makes up.
+    fn finish_capture(&mut self, token: &mut impl FnMut(Token)) {
+        let capture = match self.capture.take() {
+            Some(capture) => capture,
+            None => return
+        };
+        let text: String = capture.pieces.iter().map(|(_, text)| text.as_str()).collect();
+        let link = parse_command(&capture.template.replace("&text;", &text)).map(Link::Send);
+        for (styles, text) in &capture.pieces {
+            token(Token::Text(styles, link.as_ref(), text));
+        }
+    }
+
+    /// Handles the inside of a `<...>`. Returns false if the tag isn't one
+    /// we support in the current mode, so it should be shown as text.
+    fn tag(&mut self, tag: &str, token: &mut impl FnMut(Token)) -> bool {
+        let secure = self.mode() == Mode::Secure;
+        if let Some(name) = tag.strip_prefix('/') {
+            let name = name.trim().to_ascii_lowercase();
+            let position = match self.stack.iter().rposition(|open| open.name == name) {
+                Some(position) if secure || self.stack[position].open_mode => position,
+                _ => return false
+            };
+            if self.stack[position..].iter().any(|open| open.effects.iter().any(|effect| matches!(effect, Effect::SendText(_)))) {
+                self.finish_capture(token);
+            }
+            self.stack.truncate(position);
+            return true;
+        }
+        if tag.starts_with("!--") && tag.ends_with("--") {
+            return secure;
+        }
+
+        let (name, attributes) = parse_tag(tag);
+        if name == "!element" || name == "!el" {
+            return secure && self.define(&attributes);
+        }
+        if name == "br" {
+            let styles = Styles::new();
+            self.emit("\n", &styles, None, token);
+            return true;
+        }
+        let effects = match self.effects(&name, &attributes, secure, 0) {
+            Some(effects) => effects,
+            None => return false
+        };
+        if self.elements.get(&name).map_or(false, |element| element.empty) || self.stack.len() >= MAX_TAGS {
+            return true;
+        }
+        if let Some(template) = effects.iter().find_map(|effect| match effect {
+            Effect::SendText(template) => Some(template.clone()),
+            _ => None
+        }) {
+            self.finish_capture(token);
+            self.capture = Some(Capture { template, pieces: Vec::new(), len: 0 });
+        }
+        self.stack.push(OpenTag { name, effects, open_mode: !secure });
+        true
+    }
+
+    /// What opening the tag does, or `None` if it isn't supported or allowed.
+    fn effects(&self, name: &str, attributes: &[(Option<String>, String)], secure: bool, depth: usize) -> Option<Vec<Effect>> {
+        let effect = match name {
+            "b" | "bold" | "strong" | "h" | "high" => Effect::Bold,
+            "i" | "italic" | "em" => Effect::Italic,
+            "u" | "underline" => Effect::Underline,
+            "s" | "strikeout" => Effect::Strikeout,
+            "c" | "color" | "font" => {
+                let (fore, back) = if name == "font" { ("color", "back") } else { ("fore", "back") };
+                let mut effects = Vec::new();
+                let fore = attribute(attributes, fore, if name == "font" { 1 } else { 0 }).and_then(parse_color);
+                let back = attribute(attributes, back, if name == "font" { 2 } else { 1 }).and_then(parse_color);
+                effects.extend(fore.map(Effect::Fore));
+                effects.extend(back.map(Effect::Back));
+                return Some(effects);
+            },
+            "a" if secure => {
+                let href = attribute(attributes, "href", 0)?;
+                if !crate::ansi::is_safe_link(href) {
+                    return Some(Vec::new());
+                }
+                Effect::Link(Link::Url(href.to_string()))
+            },
+            "send" if secure => {
+                let href = attribute(attributes, "href", 0).unwrap_or("&text;");
+                if href.contains("&text;") {
+                    Effect::SendText(href.to_string())
+                } else {
+                    match parse_command(href) {
+                        Some(command) => Effect::Link(Link::Send(command)),
+                        None => return Some(Vec::new())
+                    }
+                }
+            },
+            _ => return self.expand(name, attributes, secure, depth)
+        };
+        Some(vec![effect])
+    }
+
+    /// Expands a custom element into the effects of the tags it stands for.
+    fn expand(&self, name: &str, attributes: &[(Option<String>, String)], secure: bool, depth: usize) -> Option<Vec<Effect>> {
+        let element = self.elements.get(name)?;
+        if !(secure || element.open) || depth >= MAX_EXPANSION {
+            return None;
+        }
+        let mut definition = element.definition.clone();
+        for (i, (attribute_name, default)) in element.attributes.iter().enumerate() {
+            let value = attribute(attributes, attribute_name, i).unwrap_or(default);
+            definition = definition.replace(&format!("&{};", attribute_name), value);
+        }
+        let mut effects = Vec::new();
+        let mut rest = definition.as_str();
+        while let Some(start) = rest.find('<') {
+            rest = &rest[start..];
+            let end = match tag_end(rest) {
+                Some(end) => end,
+                None => break
+            };
+            let (name, attributes) = parse_tag(&rest[1..end]);
+            effects.extend(self.effects(&name, &attributes, true, depth + 1).unwrap_or_default());
+            rest = &rest[end + 1..];
+        }
+        Some(effects)
+    }
+
+    /// Handles `<!ELEMENT name 'definition' ATT='attr=default ...' [OPEN] [EMPTY] [DELETE]>`.
+    fn define(&mut self, attributes: &[(Option<String>, String)]) -> bool {
+        let mut positional = attributes.iter().filter(|(key, _)| key.is_none()).map(|(_, value)| value.as_str());
+        let name = match positional.next() {
+            Some(name) if !name.is_empty() => name.to_ascii_lowercase(),
+            _ => return false
+        };
+        let mut definition = String::new();
+        let (mut open, mut empty) = (false, false);
+        for value in positional {
+            match value.to_ascii_lowercase().as_str() {
+                "open" => open = true,
+                "empty" => empty = true,
+                "delete" => {
+                    self.elements.remove(&name);
+                    return true;
+                },
+                _ if definition.is_empty() => definition = value.to_string(),
+                _ => {}
+            }
+        }
+        if self.elements.len() >= MAX_TAGS && !self.elements.contains_key(&name) {
+            return true;
+        }
+        let element_attributes = attributes.iter()
+            .find(|(key, _)| key.as_deref() == Some("att"))
+            .map_or("", |(_, value)| value.as_str())
+            .split_whitespace()
+            .map(|att| {
+                let (name, default) = att.split_once('=').unwrap_or((att, ""));
+                (name.to_ascii_lowercase(), default.to_string())
+            })
+            .collect();
+        self.elements.insert(name, Element { definition, attributes: element_attributes, open, empty });
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Formatting, entities, links, a `<send>` using its text, one trying to
+    /// send a proxy command, a custom element and tags that aren't supported.
+    const INPUT: &str = concat!(
+        "<b>bold</b> <color fore=red back=#000080>c</color> &lt;&amp;&#65;&nbsp;AT&T 3 < 4 ",
+        "<script>x</script> <send href=\"look\">look</send> <send>north</send> ",
+        "<send href=\"PHUD:CONNECT evil.example.org 23\">bad</send> <a href=\"https://example.org/\">site</a> ",
+        "<a href=\"javascript:alert(1)\">js</a> <!ELEMENT rx '<send href=\"go &dir;\">' ATT='dir=n'>",
+        "<rx dir=east>east</rx> <unknown attr='>'>\n<i>done</i>"
+    );
+
+    /// The text for `chunks` in secure mode, with the style and link written
+    /// out whenever they change.
+    fn render(chunks: &[&str]) -> String {
+        let mut mxp = Mxp::new(true);
+        let mut out = String::new();
+        let mut last = String::new();
+        let mut token = |token: Token| if let Token::Text(styles, link, text) = token {
+            let state = format!(
+                "[{:?} {:?} {}{}{}{} {}]",
+                styles.color.map(|color| color.to_string()),
+                styles.background.map(|color| color.to_string()),
+                styles.bold as u8, styles.italic as u8, styles.underline as u8, styles.strikethrough as u8,
+                match link {
+                    Some(Link::Url(url)) => format!("url:{}", url),
+                    Some(Link::Send(command)) => format!("send:{}", command),
+                    None => String::new()
+                }
+            );
+            if state != last {
+                out.push_str(&state);
+                last = state;
+            }
+            out.push_str(text);
+        };
+        mxp.set_mode(6, &mut token);
+        for chunk in chunks {
+            mxp.text(chunk, &Styles::new(), None, &mut token);
+        }
+        out
+    }
+
+    #[test]
+    fn applies_supported_tags() {
+        assert_eq!(render(&[INPUT]), concat!(
+            "[None None 1000 ]bold[None None 0000 ] [Some(\"#ff0000\") Some(\"#000080\") 0000 ]c[None None 0000 ] ",
+            "<&A\u{A0}AT&T 3 < 4 <script>x</script> [None None 0000 send:look]look[None None 0000 ] ",
+            "[None None 0000 send:north]north[None None 0000 ] bad ",
+            "[None None 0000 url:https://example.org/]site[None None 0000 ] js ",
+            "[None None 0000 send:go east]east[None None 0000 ] <unknown attr='>'>\n[None None 0100 ]done"
+        ));
+    }
+
+    #[test]
+    fn same_output_however_split() {
+        let whole = render(&[INPUT]);
+        for (at, _) in INPUT.char_indices().skip(1) {
+            assert_eq!(render(&[&INPUT[..at], &INPUT[at..]]), whole, "split at {}", at);
+        }
+        let chars: Vec<String> = INPUT.chars().map(String::from).collect();
+        assert_eq!(render(&chars.iter().map(String::as_str).collect::<Vec<_>>()), whole);
+    }
+
+    #[test]
+    fn sends_long_send_text_unlinked() {
+        let long = "n".repeat(MAX_COMMAND);
+        assert_eq!(render(&["<send>", &long, "</send>"]), format!("[None None 0000 send:{}]{}", long, long));
+        let chars: Vec<String> = "<send>".chars().chain(long.chars()).map(String::from).collect();
+        let mut chunks: Vec<&str> = chars.iter().map(String::as_str).collect();
+        chunks.extend(["n", "</send> north"]);
+        assert_eq!(render(&chunks), format!("[None None 0000 ]{}n north", long));
+    }
+
+    #[test]
+    fn rejects_proxy_commands() {
+        assert_eq!(parse_command("PHUD:CONNECT evil.example.org 23"), None);
+        assert_eq!(parse_command("look|PHUD:NAWS 1 1").as_deref(), Some("look"));
+        assert_eq!(parse_command("PHUD:DISCONNECT|look"), None);
+        assert_eq!(parse_command("say hi\r\nquit"), None);
+    }
+}
@@ -0,0 +1,223 @@
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+
+use anyhow::{Result, Context, bail};
+use nanoserde::DeJson;
+
+/// The `--policy` file. Every list is optional.
+#[derive(DeJson)]
+struct PolicyConfig {
+    allow_hosts: Option<Vec<String>>,
+    allow_ips: Option<Vec<String>>,
+    deny_ips: Option<Vec<String>>,
+    allow_ports: Option<Vec<String>>,
+    deny_ports: Option<Vec<String>>
+}
+
+/// Whether `host` is one of `patterns`, where `*` matches any host and
+/// `*.example.org` any subdomain of example.org. Patterns must be lowercase.
+pub fn host_matches(patterns: &[String], host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    patterns.iter().any(|pattern| {
+        match pattern.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) if suffix.starts_with('.') => host.ends_with(suffix),
+            _ => *pattern == host
+        }
+    })
+}
+
+/// IPv4 addresses written as IPv6 (`::ffff:10.0.0.1`) are compared as IPv4.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip
+    }
+}
+
+/// An address range like `10.0.0.0/8` or `2001:db8::/32`. A bare address
+/// is a range of one.
+struct Cidr {
+    network: IpAddr,
+    prefix: u8
+}
+
+impl Cidr {
+    fn parse(str: &str) -> Result<Self> {
+        let (address, prefix) = match str.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (str, None)
+        };
+        let network = canonical(address.trim().parse().with_context(|| format!("Invalid address range {}", str))?);
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().ok().filter(|&prefix| prefix <= bits)
+                .with_context(|| format!("Invalid prefix length in {}", str))?,
+            None => bits
+        };
+        Ok(Self { network, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (network, ip, bits) = match (self.network, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => (u32::from(network) as u128, u32::from(ip) as u128, 32),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false
+        };
+        self.prefix == 0 || (network ^ ip) >> (bits - self.prefix) == 0
+    }
+}
+
+/// A port like `23` or range like `4000-4999`.
+fn parse_ports(str: &str) -> Result<RangeInclusive<u16>> {
+    let (start, end) = str.split_once('-').unwrap_or((str, str));
+    let start = start.trim().parse().with_context(|| format!("Invalid port range {}", str))?;
+    let end = end.trim().parse().with_context(|| format!("Invalid port range {}", str))?;
+    if start > end {
+        bail!("Invalid port range {}", str);
+    }
+    Ok(start..=end)
+}
+
+/// Where web clients may connect to, beyond the check for private addresses.
+/// Without a `--policy` file, anything goes.
+#[derive(Default)]
+pub struct Policy {
+    allow_hosts: Option<Vec<String>>,
+    allow_ips: Option<Vec<Cidr>>,
+    deny_ips: Vec<Cidr>,
+    allow_ports: Option<Vec<RangeInclusive<u16>>>,
+    deny_ports: Vec<RangeInclusive<u16>>
+}
+
+impl Policy {
+    /// Checks a connection to `host:port`, once the host has been resolved
+    /// to `ip`. The error explains why the connection isn't allowed.
+    pub fn check(&self, host: &str, port: u16, ip: IpAddr) -> Result<()> {
+        let reason = if self.allow_hosts.as_ref().map_or(false, |hosts| !host_matches(hosts, host)) {
+            format!("{} is not one of the allowed hosts", host)
+        } else if self.deny_ports.iter().any(|ports| ports.contains(&port))
+            || self.allow_ports.as_ref().map_or(false, |allowed| !allowed.iter().any(|ports| ports.contains(&port))) {
+            format!("port {} is blocked", port)
+        } else if self.deny_ips.iter().any(|range| range.contains(ip))
+            || self.allow_ips.as_ref().map_or(false, |allowed| !allowed.iter().any(|range| range.contains(ip))) {
+            format!("the address {} is blocked", ip)
+        } else {
+            return Ok(());
+        };
+        bail!("Connections to {}:{} are not allowed by this server, as {}", host, port, reason)
+    }
+}
+
+/// Reads the policy file, a JSON object like
+/// `{"allow_hosts": ["*.example.org"], "deny_ips": ["203.0.113.0/24"], "deny_ports": ["25", "6660-6669"]}`.
+pub fn load(path: &str) -> Result<Policy> {
+    let json = std::fs::read_to_string(path).with_context(|| format!("Could not read policy file {}", path))?;
+    let config: PolicyConfig = DeJson::deserialize_json(&json)
+        .map_err(|err| anyhow::anyhow!("Invalid policy file {}: {:?}", path, err))?;
+    let cidrs = |list: Option<Vec<String>>| list.map(|list| list.iter().map(|cidr| Cidr::parse(cidr)).collect::<Result<Vec<_>>>()).transpose();
+    let ports = |list: Option<Vec<String>>| list.map(|list| list.iter().map(|ports| parse_ports(ports)).collect::<Result<Vec<_>>>()).transpose();
+    Ok(Policy {
+        allow_hosts: config.allow_hosts.map(|hosts| hosts.iter()
+            .map(|host| host.trim().trim_end_matches('.').to_ascii_lowercase())
+            .collect()),
+        allow_ips: cidrs(config.allow_ips)?,
+        deny_ips: cidrs(config.deny_ips)?.unwrap_or_default(),
+        allow_ports: ports(config.allow_ports)?,
+        deny_ports: ports(config.deny_ports)?.unwrap_or_default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(str: &str) -> IpAddr {
+        str.parse().unwrap()
+    }
+
+    fn cidr(str: &str) -> Cidr {
+        Cidr::parse(str).unwrap()
+    }
+
+    fn hosts(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn matches_address_ranges() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(cidr("203.0.113.7/32").contains(ip("203.0.113.7")));
+        assert!(!cidr("203.0.113.7").contains(ip("203.0.113.8")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn compares_mapped_ipv6_as_ipv4() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+        assert!(cidr("::ffff:10.0.0.0/8").contains(ip("10.0.0.1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn rejects_bad_address_ranges() {
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("2001:db8::/129").is_err());
+        assert!(Cidr::parse("example.org/8").is_err());
+        assert!(Cidr::parse("10.0.0.0/").is_err());
+    }
+
+    #[test]
+    fn parses_ports() {
+        assert_eq!(parse_ports("23").unwrap(), 23..=23);
+        assert_eq!(parse_ports(" 4000 - 4999 ").unwrap(), 4000..=4999);
+        assert!(parse_ports("4999-4000").is_err());
+        assert!(parse_ports("telnet").is_err());
+        assert!(parse_ports("23-").is_err());
+        assert!(parse_ports("65536").is_err());
+    }
+
+    #[test]
+    fn matches_hosts() {
+        assert!(host_matches(&hosts(&["*"]), "mud.example.org"));
+        assert!(host_matches(&hosts(&["*.example.org"]), "mud.example.org"));
+        assert!(!host_matches(&hosts(&["*.example.org"]), "example.org"));
+        assert!(!host_matches(&hosts(&["*.example.org"]), "badexample.org"));
+        assert!(host_matches(&hosts(&["mud.example.org"]), "MUD.Example.org."));
+        assert!(!host_matches(&hosts(&["mud.example.org"]), "mud.example.org.evil.net"));
+        assert!(!host_matches(&[], "mud.example.org"));
+    }
+
+    #[test]
+    fn allows_anything_without_policy() {
+        assert!(Policy::default().check("mud.example.org", 25, ip("10.0.0.1")).is_ok());
+    }
+
+    #[test]
+    fn checks_host_then_port_then_address() {
+        let policy = Policy {
+            allow_hosts: Some(hosts(&["*.example.org"])),
+            allow_ips: Some(vec![cidr("203.0.113.0/24")]),
+            deny_ips: vec![cidr("203.0.113.66")],
+            allow_ports: Some(vec![23..=23, 4000..=4999]),
+            deny_ports: vec![4444..=4444]
+        };
+        let reason = |host, port, address| policy.check(host, port, ip(address)).unwrap_err().to_string();
+        assert!(policy.check("mud.example.org", 4000, ip("203.0.113.7")).is_ok());
+        assert_eq!(reason("mud.evil.net", 25, "10.0.0.1"),
+            "Connections to mud.evil.net:25 are not allowed by this server, as mud.evil.net is not one of the allowed hosts");
+        assert_eq!(reason("mud.example.org", 25, "10.0.0.1"),
+            "Connections to mud.example.org:25 are not allowed by this server, as port 25 is blocked");
+        assert_eq!(reason("mud.example.org", 4444, "203.0.113.7"),
+            "Connections to mud.example.org:4444 are not allowed by this server, as port 4444 is blocked");
+        assert_eq!(reason("mud.example.org", 23, "10.0.0.1"),
+            "Connections to mud.example.org:23 are not allowed by this server, as the address 10.0.0.1 is blocked");
+        assert_eq!(reason("mud.example.org", 23, "203.0.113.66"),
+            "Connections to mud.example.org:23 are not allowed by this server, as the address 203.0.113.66 is blocked");
+    }
+}
@@ -0,0 +1,534 @@
+use std::fmt::Write;
+
+use lazy_static::lazy_static;
+use nanoserde::SerJson;
+use regex::Regex;
+
+use crate::ansi::{self, AnsiParser, Color, Link, Styles, Token};
+use crate::rules::{RuleSet, Tail};
+
+/// A piece of rendered MUD output.
+pub enum Output {
+    /// The JSON value sent to the web client as the `message` or `prompt` field.
+    Text(String),
+    /// Everything shown so far should be cleared.
+    Clear
+}
+
+/// Turns decoded MUD output into what is sent to the web client.
+/// Renderers are kept per connection, as colors set by the MUD stay in
+/// effect across reads.
+pub trait Renderer: Send {
+    /// `tail` says what to do with the text after the last line break.
+    fn render(&mut self, text: &str, tail: Tail) -> Vec<Output>;
+
+    /// Starts or stops parsing MXP tags, as the MUD turns MXP on or off.
+    fn set_mxp(&mut self, enabled: bool);
+}
+
+/// The output formats a web client can ask for with `renderer=<name>` in `PHUD:CONNECT`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    /// An HTML string using `<span>`s and `<br>`, for `static/js/client.js`.
+    Html,
+    /// A plain string with the escape sequences removed.
+    Text,
+    /// An array of `{text, fg, bg, bold, ...}` objects, for clients that
+    /// don't render HTML.
+    Spans
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "html" => Some(Format::Html),
+            "text" => Some(Format::Text),
+            "spans" => Some(Format::Spans),
+            _ => None
+        }
+    }
+
+    pub fn renderer(self, color: bool, rules: RuleSet) -> Box<dyn Renderer> {
+        let source = Source::new(color, rules);
+        match self {
+            Format::Html => Box::new(HtmlRenderer { source }),
+            Format::Text => Box::new(TextRenderer { source }),
+            Format::Spans => Box::new(SpanRenderer { source })
+        }
+    }
+}
+
+/// What every renderer does before formatting: clean up the MUD's text,
+/// run the connection's rules over it and split it into tokens.
+struct Source {
+    ansi: AnsiParser,
+    rules: RuleSet,
+    /// The cleaned text, kept so it doesn't need allocating on every read.
+    buffer: String,
+    /// `\r` or `\n` if the last read ended in a line break. A read starting
+    /// with the other one finishes that line break rather than adding one.
+    newline: Option<u8>
+}
+
+impl Source {
+    fn new(color: bool, rules: RuleSet) -> Self {
+        Self { ansi: AnsiParser::new(color), rules, buffer: String::new(), newline: None }
+    }
+
+    fn parse(&mut self, text: &str, tail: Tail, token: impl FnMut(Token)) {
+        self.clean(text);
+        let text = self.rules.apply(&self.buffer, tail);
+        self.ansi.parse(&text, token);
+    }
+
+    /// Copies `text` into `buffer` in one pass, turning `\r\n`, `\n\r`, `\r`
+    /// and `\n` into `\n`, expanding tabs and removing control characters apart
+    /// from ESC and BEL, which `AnsiParser` still needs.
+    fn clean(&mut self, text: &str) {
+        self.buffer.clear();
+        let bytes = text.as_bytes();
+        let mut newline = self.newline.take();
+        // Start of the bytes not copied yet.
+        let mut start = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            let len = match bytes[i] {
+                0x00..=0x1F | 0x7F => 1,
+                // C1 control characters, U+0080 to U+009F.
+                0xC2 if matches!(bytes.get(i + 1), Some(0x80..=0x9F)) => 2,
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            if i > start {
+                self.buffer.push_str(&text[start..i]);
+                newline = None;
+            }
+            match bytes[i] {
+                byte @ (b'\r' | b'\n') => {
+                    if newline.is_some() && newline != Some(byte) {
+                        newline = None;
+                    } else {
+                        self.buffer.push('\n');
+                        newline = Some(byte);
+                    }
+                },
+                byte => {
+                    match byte {
+                        b'\t' => self.buffer.push_str("     "),
+                        b'\x1B' | b'\x07' => self.buffer.push(byte as char),
+                        _ => {}
+                    }
+                    newline = None;
+                }
+            }
+            i += len;
+            start = i;
+        }
+        if start < bytes.len() {
+            self.buffer.push_str(&text[start..]);
+            newline = None;
+        }
+        self.newline = newline;
+    }
+}
+
+lazy_static! {
+    static ref URL: Regex = Regex::new(r#"(?i)\bhttps?://[^\s<>"']+"#).unwrap();
+}
+
+/// Splits `text` around the http(s) URLs in it, calling `piece` with each
+/// part and, for URLs, the link.
+fn linkify(text: &str, mut piece: impl FnMut(&str, Option<&Link>)) {
+    // Most text has no URLs, and this is much quicker than the regex.
+    if memchr::memmem::find(text.as_bytes(), b"://").is_none() {
+        piece(text, None);
+        return;
+    }
+    let mut last = 0;
+    for found in URL.find_iter(text) {
+        let mut url = found.as_str();
+        // Leave off punctuation that most likely ends the sentence, not the URL.
+        while let Some(c) = url.chars().last() {
+            if ".,;:!?]".contains(c) || (c == ')' && url.matches(')').count() > url.matches('(').count()) {
+                url = &url[..url.len() - 1];
+            } else {
+                break;
+            }
+        }
+        if !ansi::is_safe_link(url) {
+            continue;
+        }
+        if found.start() > last {
+            piece(&text[last..found.start()], None);
+        }
+        piece(url, Some(&Link::Url(url.to_string())));
+        last = found.start() + url.len();
+    }
+    if last < text.len() {
+        piece(&text[last..], None);
+    }
+}
+
+/// Appends `text` to the JSON string being built in `json`, escaped.
+fn escape_json(json: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_ascii_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            },
+            c => json.push(c)
+        }
+    }
+}
+
+/// Appends `text` to the JSON string being built in `json`, escaped for use
+/// in HTML content or a quoted attribute and then for JSON.
+fn escape_html(json: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => json.push_str("&amp;"),
+            '<' => json.push_str("&lt;"),
+            '>' => json.push_str("&gt;"),
+            '"' => json.push_str("&quot;"),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("<br>"),
+            c if c.is_ascii_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            },
+            c => json.push(c)
+        }
+    }
+}
+
+/// Ends the JSON string in `json` and adds it to `out`, unless nothing was
+/// written to it, then starts a new one.
+fn finish_json(json: &mut String, out: &mut Vec<Output>) {
+    if json.len() > 1 {
+        json.push('"');
+        out.push(Output::Text(std::mem::replace(json, String::from('"'))));
+    }
+}
+
+pub struct HtmlRenderer {
+    source: Source
+}
+
+impl HtmlRenderer {
+    /// Writes the opening tag of a span in `styles` to `json`, with its
+    /// attribute quotes escaped.
+    fn open_span(styles: &Styles, json: &mut String) {
+        // The 16 named colors are left to `static/css/telnet.css`.
+        let basic = |color: Option<Color>| color.filter(Color::is_basic);
+        let other = |color: Option<Color>| color.filter(|color| !color.is_basic());
+
+        json.push_str("<span");
+        let classes = [
+            (styles.blink, "tnc_blink"),
+            (styles.inverse, "tnc_inverse"),
+            (styles.strikethrough, "tnc_line_through"),
+            (styles.underline, "tnc_underline"),
+            (styles.bold, "tnc_bold"),
+            (styles.italic, "tnc_italic")
+        ];
+        let mut separator = " class=\\\"";
+        if let Some(background) = basic(styles.background) {
+            let _ = write!(json, "{}tnc_bg_{}", separator, background);
+            separator = " ";
+        }
+        if let Some(color) = basic(styles.color) {
+            let _ = write!(json, "{}tnc_{}", separator, color);
+            separator = " ";
+        }
+        for class in classes.iter().filter(|(set, _)| *set).map(|(_, class)| *class).chain(styles.highlight) {
+            json.push_str(separator);
+            json.push_str(class);
+            separator = " ";
+        }
+        if separator == " " {
+            json.push_str("\\\"");
+        }
+
+        let mut separator = " style=\\\"";
+        if let Some(background) = other(styles.background) {
+            let _ = write!(json, "{}background-color: {};", separator, background);
+            separator = " ";
+        }
+        if let Some(color) = other(styles.color) {
+            let _ = write!(json, "{}color: {};", separator, color);
+            separator = " ";
+        }
+        if separator == " " {
+            json.push_str("\\\"");
+        }
+        json.push('>');
+    }
+}
+
+impl Renderer for HtmlRenderer {
+    /// Each piece of output is self-contained, with every span closed. The
+    /// HTML is written straight into a JSON string, escaped as it goes.
+    fn render(&mut self, text: &str, tail: Tail) -> Vec<Output> {
+        let mut out = Vec::new();
+        let mut json = String::with_capacity(text.len() + 32);
+        json.push('"');
+        // Style of the span currently open in `json`, if any.
+        let mut open: Option<Styles> = None;
+        self.source.parse(text, tail, |token| match token {
+            Token::Text(styles, link, text) => {
+                let current = Some(*styles).filter(|styles| *styles != Styles::new());
+                if open != current {
+                    if open.is_some() {
+                        json.push_str("</span>");
+                    }
+                    if let Some(styles) = current {
+                        HtmlRenderer::open_span(&styles, &mut json);
+                    }
+                    open = current;
+                }
+                let mut push = |text: &str, link: Option<&Link>| {
+                    match link {
+                        Some(Link::Url(url)) => {
+                            json.push_str(r#"<a href=\""#);
+                            escape_html(&mut json, url);
+                            json.push_str(r#"\" target=\"_blank\" rel=\"noopener\">"#);
+                        },
+                        // Clicks are picked up by `static/js/client.js`.
+                        Some(Link::Send(command)) => {
+                            json.push_str(r#"<a class=\"mxp_send\" data-send=\""#);
+                            escape_html(&mut json, command);
+                            json.push_str(r#"\" title=\""#);
+                            escape_html(&mut json, command);
+                            json.push_str(r#"\">"#);
+                        },
+                        None => {}
+                    }
+                    escape_html(&mut json, text);
+                    if link.is_some() {
+                        json.push_str("</a>");
+                    }
+                };
+                match link {
+                    Some(link) => push(text, Some(link)),
+                    None => linkify(text, push)
+                }
+            },
+            Token::Clear => {
+                if open.take().is_some() {
+                    json.push_str("</span>");
+                }
+                finish_json(&mut json, &mut out);
+                out.push(Output::Clear);
+            }
+        });
+        if open.is_some() {
+            json.push_str("</span>");
+        }
+        finish_json(&mut json, &mut out);
+        out
+    }
+
+    fn set_mxp(&mut self, enabled: bool) {
+        self.source.ansi.set_mxp(enabled);
+    }
+}
+
+pub struct TextRenderer {
+    source: Source
+}
+
+impl Renderer for TextRenderer {
+    fn render(&mut self, text: &str, tail: Tail) -> Vec<Output> {
+        let mut out = Vec::new();
+        let mut json = String::with_capacity(text.len() + 2);
+        json.push('"');
+        self.source.parse(text, tail, |token| match token {
+            Token::Text(_, _, text) => escape_json(&mut json, text),
+            Token::Clear => {
+                finish_json(&mut json, &mut out);
+                out.push(Output::Clear);
+            }
+        });
+        finish_json(&mut json, &mut out);
+        out
+    }
+
+    fn set_mxp(&mut self, enabled: bool) {
+        self.source.ansi.set_mxp(enabled);
+    }
+}
+
+/// A run of text in one style. Colors are named (`red`, `bright_blue`)
+/// for the 16 basic colors and `#rrggbb` otherwise, and left out when unset.
+/// `link` is set for OSC 8 hyperlinks, MXP `<a>` and URLs found in the
+/// text, and `send` for MXP `<send>` commands.
+#[derive(SerJson)]
+struct Span {
+    text: String,
+    link: Option<String>,
+    send: Option<String>,
+    fg: Option<String>,
+    bg: Option<String>,
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    strikethrough: bool,
+    blink: bool,
+    inverse: bool,
+    /// Class of the highlight rule that matched the text, if any.
+    highlight: Option<String>
+}
+
+pub struct SpanRenderer {
+    source: Source
+}
+
+impl SpanRenderer {
+    fn to_json(spans: Vec<(Styles, Option<Link>, String)>) -> String {
+        spans.into_iter().map(|(styles, link, text)| Span {
+            text,
+            link: match &link {
+                Some(Link::Url(url)) => Some(url.clone()),
+                _ => None
+            },
+            send: match link {
+                Some(Link::Send(command)) => Some(command),
+                _ => None
+            },
+            fg: styles.color.map(|color| color.to_string()),
+            bg: styles.background.map(|color| color.to_string()),
+            bold: styles.bold,
+            italic: styles.italic,
+            underline: styles.underline,
+            strikethrough: styles.strikethrough,
+            blink: styles.blink,
+            inverse: styles.inverse,
+            highlight: styles.highlight.map(str::to_string)
+        }).collect::<Vec<Span>>().serialize_json()
+    }
+}
+
+impl Renderer for SpanRenderer {
+    fn render(&mut self, text: &str, tail: Tail) -> Vec<Output> {
+        let mut out = Vec::new();
+        let mut spans: Vec<(Styles, Option<Link>, String)> = Vec::new();
+        self.source.parse(text, tail, |token| match token {
+            Token::Text(styles, link, text) => {
+                let mut push = |text: &str, link: Option<&Link>| match spans.last_mut() {
+                    Some((last, last_link, last_text)) if last == styles && last_link.as_ref() == link => last_text.push_str(text),
+                    _ => spans.push((*styles, link.cloned(), text.to_string()))
+                };
+                match link {
+                    Some(link) => push(text, Some(link)),
+                    None => linkify(text, push)
+                }
+            },
+            Token::Clear => {
+                if !spans.is_empty() {
+                    out.push(Output::Text(SpanRenderer::to_json(std::mem::take(&mut spans))));
+                }
+                out.push(Output::Clear);
+            }
+        });
+        if !spans.is_empty() {
+            out.push(Output::Text(SpanRenderer::to_json(spans)));
+        }
+        out
+    }
+
+    fn set_mxp(&mut self, enabled: bool) {
+        self.source.ansi.set_mxp(enabled);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outputs(renderer: &mut dyn Renderer, text: &str) -> Vec<String> {
+        renderer.render(text, Tail::End).into_iter().map(|output| match output {
+            Output::Text(json) => json,
+            Output::Clear => "clear".to_string()
+        }).collect()
+    }
+
+    fn render(format: Format, color: bool, text: &str) -> Vec<String> {
+        outputs(format.renderer(color, RuleSet::default()).as_mut(), text)
+    }
+
+    fn render_html(text: &str) -> Vec<String> {
+        let mut renderer = Format::Html.renderer(true, RuleSet::default());
+        renderer.set_mxp(true);
+        outputs(renderer.as_mut(), text)
+    }
+
+    #[test]
+    fn escapes_unsupported_mxp_tags() {
+        assert_eq!(
+            render_html("\x1B[1z<script>alert(1)</script> <img src=x onerror=alert(1)>"),
+            [r#""&lt;script&gt;alert(1)&lt;/script&gt; &lt;img src=x onerror=alert(1)&gt;""#]
+        );
+    }
+
+    #[test]
+    fn links_only_safe_mxp_commands() {
+        assert_eq!(
+            render_html("\x1B[1z<send href=\"PHUD:CONNECT evil.example.org 23\">bad</send> <send>\"look\"</send>"),
+            [r#""bad <a class=\"mxp_send\" data-send=\"&quot;look&quot;\" title=\"&quot;look&quot;\">&quot;look&quot;</a>""#]
+        );
+    }
+
+    #[test]
+    fn leaves_out_styles_without_color() {
+        let text = "\x1B[1;31mred\x1B[0m \x1B[38;5;208mand\x1B[0m \x1B[48;2;1;2;3mmore";
+        assert_eq!(render(Format::Html, false, text), [r#""red and more""#]);
+        assert_eq!(render(Format::Spans, false, "\x1B[1;31mred"), [r#"[{"text":"red","bold":false,"italic":false,"underline":false,"strikethrough":false,"blink":false,"inverse":false}]"#]);
+    }
+
+    #[test]
+    fn names_basic_colors_as_classes() {
+        assert_eq!(
+            render(Format::Html, true, "\x1B[1;31;44mHP\x1B[0m \x1B[3;4;93mlow"),
+            [r#""<span class=\"tnc_bg_blue tnc_red tnc_bold\">HP</span> <span class=\"tnc_bright_yellow tnc_underline tnc_italic\">low</span>""#]
+        );
+    }
+
+    #[test]
+    fn styles_other_colors_inline() {
+        assert_eq!(
+            render(Format::Html, true, "\x1B[38;5;208mfire\x1B[0m \x1B[1;38;2;1;2;3;48;5;17mwater"),
+            [r#""<span style=\"color: #ff8700;\">fire</span> <span class=\"tnc_bold\" style=\"background-color: #00005f; color: #010203;\">water</span>""#]
+        );
+    }
+
+    #[test]
+    fn escapes_html_and_json() {
+        let text = r#"Bob says, "a & b < c > d \ e""#;
+        assert_eq!(render(Format::Html, true, text), [r#""Bob says, &quot;a &amp; b &lt; c &gt; d \\ e&quot;""#]);
+        assert_eq!(render(Format::Text, true, text), [r#""Bob says, \"a & b < c > d \\ e\"""#]);
+    }
+
+    #[test]
+    fn strips_escape_sequences_from_text() {
+        assert_eq!(
+            render(Format::Text, true, "\x1B[1;31mHP\x1B[0m:\t10\x07\r\n\x1B]8;;https://example.org\x1B\\link\x1B]8;;\x1B\\"),
+            [r#""HP:     10\nlink""#]
+        );
+    }
+
+    #[test]
+    fn leaves_unset_span_fields_out() {
+        assert_eq!(
+            render(Format::Spans, true, "\x1B[1;31mHP\x1B[0m see https://example.org"),
+            [concat!(
+                r#"[{"text":"HP","fg":"red","bold":true,"italic":false,"underline":false,"strikethrough":false,"blink":false,"inverse":false},"#,
+                r#"{"text":" see ","bold":false,"italic":false,"underline":false,"strikethrough":false,"blink":false,"inverse":false},"#,
+                r#"{"text":"https://example.org","link":"https://example.org","bold":false,"italic":false,"underline":false,"strikethrough":false,"blink":false,"inverse":false}]"#
+            )]
+        );
+    }
+}
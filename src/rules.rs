@@ -0,0 +1,337 @@
+use std::borrow::Cow;
+
+use anyhow::{Result, Context, bail};
+use nanoserde::DeJson;
+use regex::{Captures, Regex};
+
+use crate::ansi;
+use crate::config::get_config;
+use crate::policy;
+
+/// Marks the start of highlighted text, followed by the rule's index and
+/// `HIGHLIGHT_END`. `HIGHLIGHT` directly followed by `HIGHLIGHT_END` ends it.
+/// Control characters from the MUD are removed before rules run, so the MUD
+/// can't forge these.
+pub const HIGHLIGHT: char = '\u{0E}';
+pub const HIGHLIGHT_END: char = '\u{0F}';
+
+/// One entry of the `--rules` file.
+#[derive(DeJson)]
+struct RuleConfig {
+    hosts: Option<Vec<String>>,
+    pattern: String,
+    action: String,
+    replace: Option<String>,
+    class: Option<String>
+}
+
+pub enum Action {
+    /// Replaces each match, with `$1` etc. standing for capture groups.
+    Replace(String),
+    /// Drops every line with a match.
+    Gag,
+    /// Wraps each match in a span with this class.
+    Highlight(String)
+}
+
+pub struct Rule {
+    /// Hostnames the rule applies to. `*` matches any host, and `*.example.org`
+    /// any subdomain of example.org.
+    hosts: Vec<String>,
+    pattern: Regex,
+    action: Action
+}
+
+/// Reads the rules file, a JSON array like
+/// `[{"hosts": ["mud.example.org"], "pattern": "^\\[OOC\\]", "action": "gag"}]`.
+pub fn load(path: &str) -> Result<Vec<Rule>> {
+    let json = std::fs::read_to_string(path).with_context(|| format!("Could not read rules file {}", path))?;
+    let configs: Vec<RuleConfig> = DeJson::deserialize_json(&json)
+        .map_err(|err| anyhow::anyhow!("Invalid rules file {}: {:?}", path, err))?;
+    configs.into_iter().enumerate().map(|(i, config)| {
+        let pattern = Regex::new(&config.pattern).with_context(|| format!("Invalid pattern in rule {}", i + 1))?;
+        let action = match config.action.as_str() {
+            "replace" => Action::Replace(config.replace.with_context(|| format!("Rule {} needs a \"replace\" value", i + 1))?),
+            "gag" => Action::Gag,
+            "highlight" => {
+                let class = config.class.with_context(|| format!("Rule {} needs a \"class\" value", i + 1))?;
+                if class.is_empty() || !class.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                    bail!("The class in rule {} may only contain letters, digits, - and _", i + 1);
+                }
+                Action::Highlight(class)
+            },
+            action => bail!("Unknown action \"{}\" in rule {} (replace, gag, highlight)", action, i + 1)
+        };
+        let hosts = config.hosts.unwrap_or_else(|| vec!["*".to_string()])
+            .into_iter()
+            .map(|host| host.trim().trim_end_matches('.').to_ascii_lowercase())
+            .collect();
+        Ok(Rule { hosts, pattern, action })
+    }).collect()
+}
+
+/// The class of a highlight rule, by its index in the rules file.
+pub fn highlight_class(index: usize) -> Option<&'static str> {
+    match get_config().rules.get(index) {
+        Some(Rule { action: Action::Highlight(class), .. }) => Some(class),
+        _ => None
+    }
+}
+
+/// Longest unfinished line held back, for the rest of it or for a GA or EOR.
+/// Anything longer is sent on as it is, as it's unlikely to be a prompt.
+pub const MAX_HELD: usize = 8 * 1024;
+
+/// What `RuleSet::apply` does with the text after the last line break.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Tail {
+    /// Hold it back until the rest of the line arrives.
+    Hold,
+    /// Send it now, for a prompt the MUD doesn't mark with GA or EOR, but
+    /// keep it to match the whole line again once it is finished.
+    Show,
+    /// Match it as a line of its own, as it ends a prompt or the output.
+    End
+}
+
+/// The rules for one connection, in the order they appear in the rules file.
+#[derive(Default)]
+pub struct RuleSet {
+    rules: Vec<(usize, &'static Rule)>,
+    /// The text after the last line break so far, held back until the rest
+    /// of the line arrives.
+    partial: String,
+    /// What was sent for `partial` with `Tail::Show`.
+    shown: String
+}
+
+impl RuleSet {
+    pub fn for_host(host: &str) -> Self {
+        Self {
+            rules: get_config().rules.iter()
+                .enumerate()
+                .filter(|(_, rule)| policy::host_matches(&rule.hosts, host))
+                .collect(),
+            ..Self::default()
+        }
+    }
+
+    /// A rule set for tests, with each rule applying to every host.
+    #[cfg(test)]
+    pub fn from_rules(rules: Vec<(&str, Action)>) -> Self {
+        Self {
+            rules: rules.into_iter().map(|(pattern, action)| {
+                let rule = Rule { hosts: vec!["*".to_string()], pattern: Regex::new(pattern).unwrap(), action };
+                &*Box::leak(Box::new(rule))
+            }).enumerate().collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Runs the rules over each line of `text`. Patterns are matched against
+    /// the visible text, without ANSI escape sequences or the line break.
+    /// The text after the last line break is handled as `tail` says, and
+    /// held text is put in front of the next call's, so a line split across
+    /// reads is matched as a whole. Of a line that was partly shown already,
+    /// only the rest is sent, or all of it on a new line if the rules now
+    /// make something else of the part that was shown.
+    pub fn apply<'a>(&mut self, text: &'a str, tail: Tail) -> Cow<'a, str> {
+        if self.rules.is_empty() {
+            return Cow::Borrowed(text);
+        }
+        let text = if self.partial.is_empty() {
+            Cow::Borrowed(text)
+        } else {
+            self.partial.push_str(text);
+            Cow::Owned(std::mem::take(&mut self.partial))
+        };
+        let (lines, rest) = text.split_at(text.rfind('\n').map_or(0, |i| i + 1));
+        let tail = if rest.len() > MAX_HELD { Tail::End } else { tail };
+        if tail == Tail::Hold && lines.is_empty() {
+            self.partial.push_str(rest);
+            return Cow::Borrowed("");
+        }
+        let shown = std::mem::take(&mut self.shown);
+        let mut out = self.apply_lines(lines);
+        match tail {
+            Tail::Hold => self.partial.push_str(rest),
+            Tail::Show => {
+                // An escape sequence cut off at the end waits for the rest of it,
+                // so none is left unfinished if the line turns out to be gagged.
+                let line = self.apply_lines(&rest[..ansi::finished_len(rest)]);
+                out.push_str(&line);
+                self.partial.push_str(rest);
+                self.shown = line;
+            },
+            Tail::End => out.push_str(&self.apply_lines(rest))
+        }
+        if out.starts_with(&shown) {
+            out.drain(..shown.len());
+        } else if !visible(&shown).0.is_empty() {
+            out.insert(0, '\n');
+        }
+        Cow::Owned(out)
+    }
+
+    fn apply_lines(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        'lines: for line in text.split_inclusive('\n') {
+            let (line, newline) = match line.strip_suffix('\n') {
+                Some(line) => (line, "\n"),
+                None => (line, "")
+            };
+            let mut line = Cow::Borrowed(line);
+            for (index, rule) in &self.rules {
+                match &rule.action {
+                    Action::Gag => if rule.pattern.is_match(&visible(&line).0) {
+                        continue 'lines;
+                    },
+                    Action::Replace(replace) => line = Cow::Owned(rewrite(&line, &rule.pattern, |captures, original| {
+                        let mut replaced = String::new();
+                        captures.expand(replace, &mut replaced);
+                        // Keep any change of style that was inside the match.
+                        replaced.push_str(&hidden(original));
+                        replaced
+                    })),
+                    Action::Highlight(_) => line = Cow::Owned(rewrite(&line, &rule.pattern, |_, original| {
+                        format!("{}{}{}{}{}{}", HIGHLIGHT, index, HIGHLIGHT_END, original, HIGHLIGHT, HIGHLIGHT_END)
+                    }))
+                }
+            }
+            out.push_str(&line);
+            out.push_str(newline);
+        }
+        out
+    }
+}
+
+/// Length of the escape sequence, BEL or highlight marker at the start of
+/// `str`, or 0 if it starts with a visible character.
+fn hidden_len(str: &str) -> usize {
+    match str.chars().next() {
+        Some('\x1B') => ansi::escape_len(str),
+        Some('\x07') => 1,
+        Some(HIGHLIGHT) => str.find(HIGHLIGHT_END).map_or(1, |end| end + HIGHLIGHT_END.len_utf8()),
+        _ => 0
+    }
+}
+
+/// The visible text of `line`, and for each of its bytes, its offset in `line`.
+fn visible(line: &str) -> (String, Vec<usize>) {
+    let mut text = String::with_capacity(line.len());
+    let mut offsets = Vec::with_capacity(line.len());
+    let mut i = 0;
+    while let Some(c) = line[i..].chars().next() {
+        let len = hidden_len(&line[i..]);
+        if len > 0 {
+            i += len;
+            continue;
+        }
+        text.push(c);
+        offsets.extend(i..i + c.len_utf8());
+        i += c.len_utf8();
+    }
+    (text, offsets)
+}
+
+/// Everything in `text` that `visible` leaves out.
+fn hidden(text: &str) -> String {
+    let mut hidden = String::new();
+    let mut i = 0;
+    while let Some(c) = text[i..].chars().next() {
+        match hidden_len(&text[i..]) {
+            0 => i += c.len_utf8(),
+            len => {
+                hidden.push_str(&text[i..i + len]);
+                i += len;
+            }
+        }
+    }
+    hidden
+}
+
+/// Replaces each match of `pattern` in the visible text of `line` with what
+/// `replacement` makes of it, given the captures and the matched part of `line`.
+fn rewrite(line: &str, pattern: &Regex, mut replacement: impl FnMut(&Captures, &str) -> String) -> String {
+    let (text, offsets) = visible(line);
+    let mut out = String::with_capacity(line.len());
+    let mut last = 0;
+    for captures in pattern.captures_iter(&text) {
+        let found = captures.get(0).unwrap();
+        let start = offsets.get(found.start()).copied().unwrap_or(line.len());
+        let end = if found.end() > found.start() { offsets[found.end() - 1] + 1 } else { start };
+        out.push_str(&line[last..start]);
+        out.push_str(&replacement(&captures, &line[start..end]));
+        last = end;
+    }
+    out.push_str(&line[last..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> RuleSet {
+        RuleSet::from_rules(vec![
+            (r"^\[OOC\]", Action::Gag),
+            ("Bob", Action::Replace("Robert".to_string())),
+            ("tree", Action::Highlight("plant".to_string()))
+        ])
+    }
+
+    const INPUT: &str = "\x1B[32m[OOC]\x1B[0m Bob: hi\nBob is by a tree.\nHP: 10> ";
+
+    #[test]
+    fn applies_rules_per_line() {
+        let mut rules = rules();
+        assert_eq!(rules.apply(INPUT, Tail::Hold), "Robert is by a \u{0E}2\u{0F}tree\u{0E}\u{0F}.\n");
+        assert_eq!(rules.apply("", Tail::End), "HP: 10> ");
+    }
+
+    #[test]
+    fn gags_line_split_across_reads() {
+        // Including splits inside the color code in front of `[OOC]`.
+        let whole = rules().apply(INPUT, Tail::End).into_owned();
+        for (at, _) in INPUT.char_indices().skip(1) {
+            let mut rules = rules();
+            let out = rules.apply(&INPUT[..at], Tail::Hold).into_owned() + &rules.apply(&INPUT[at..], Tail::End);
+            assert_eq!(out, whole, "split at {}", at);
+        }
+    }
+
+    #[test]
+    fn matches_shown_line_again_once_finished() {
+        let mut rules = rules();
+        assert_eq!(rules.apply("HP: 10> ", Tail::Show), "HP: 10> ");
+        assert_eq!(rules.apply("", Tail::Show), "");
+        assert_eq!(rules.apply("Bob\n", Tail::Hold), "Robert\n");
+        assert_eq!(rules.apply("\x1B[3", Tail::Show), "");
+        assert_eq!(rules.apply("2m[OOC]\x1B[0m Bob: hi\nBob", Tail::Show), "Robert");
+        // What was shown can't be taken back, so the line is ended instead.
+        assert_eq!(rules.apply(" and Alice\n", Tail::Hold), " and Alice\n");
+        assert_eq!(rules.apply("[OO", Tail::Show), "[OO");
+        assert_eq!(rules.apply("C] Bob: hi\n", Tail::Hold), "\n");
+    }
+
+    #[test]
+    fn sends_long_unfinished_line() {
+        let mut rules = rules();
+        let long = "x".repeat(MAX_HELD);
+        assert_eq!(rules.apply(&long, Tail::Hold), "");
+        assert_eq!(rules.apply("x", Tail::Hold), long + "x");
+    }
+
+    #[test]
+    fn gags_prompt() {
+        let mut rules = RuleSet::from_rules(vec![("^HP:", Action::Gag)]);
+        assert_eq!(rules.apply("HP: 10> ", Tail::Show), "");
+        assert_eq!(rules.apply("", Tail::End), "");
+        assert_eq!(rules.apply("Bob is here.\nHP: 10> ", Tail::End), "Bob is here.\n");
+    }
+
+    #[test]
+    fn passes_text_through_without_rules() {
+        assert!(matches!(RuleSet::default().apply("HP: 10> ", Tail::Hold), Cow::Borrowed("HP: 10> ")));
+    }
+}
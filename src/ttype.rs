@@ -0,0 +1,96 @@
+use crate::charset::Charset;
+
+/// Telnet option number for TTYPE.
+pub const TTYPE: u8 = 24;
+
+const IS: u8 = 0;
+const SEND: u8 = 1;
+
+/// MTTS capability bits, see https://tintin.mudhalla.net/protocols/mtts/
+const MTTS_ANSI: u32 = 1;
+const MTTS_UTF_8: u32 = 4;
+const MTTS_256_COLORS: u32 = 8;
+const MTTS_PROXY: u32 = 128;
+const MTTS_TRUECOLOR: u32 = 256;
+
+/// What the proxy and the web client can display between them, with the MUD
+/// output in `charset` and colors shown only if `color` is set.
+pub fn mtts(charset: Charset, color: bool) -> u32 {
+    let utf_8 = if charset == Charset::Utf8 { MTTS_UTF_8 } else { 0 };
+    let colors = if color { MTTS_ANSI | MTTS_256_COLORS | MTTS_TRUECOLOR } else { 0 };
+    colors | utf_8 | MTTS_PROXY
+}
+
+/// Answers successive `SB TTYPE SEND` requests following the MTTS
+/// convention: first the client name, then the terminal type, then
+/// `MTTS <bitvector>`, which is repeated from then on to mark the end of the list.
+pub struct TerminalType {
+    stage: usize
+}
+
+impl TerminalType {
+    pub fn new() -> Self {
+        Self { stage: 0 }
+    }
+
+    /// Starts the cycle over, e.g. when the MUD renegotiates TTYPE.
+    pub fn reset(&mut self) {
+        self.stage = 0;
+    }
+
+    /// Returns the `IS <name>` payload if `buffer` is a `SEND` request.
+    /// `charset` is the one currently used for the MUD's output, and `color`
+    /// whether colors are shown on this connection.
+    pub fn respond(&mut self, buffer: &[u8], client_name: &str, charset: Charset, color: bool) -> Option<Vec<u8>> {
+        if buffer.first() != Some(&SEND) {
+            return None;
+        }
+        let name = match self.stage {
+            0 => client_name.to_ascii_uppercase(),
+            // MTTS reserves "XTERM" for terminals with 256 colors, and "DUMB"
+            // for those without any.
+            1 if color => "XTERM".to_string(),
+            1 => "DUMB".to_string(),
+            _ => format!("MTTS {}", mtts(charset, color))
+        };
+        self.stage = (self.stage + 1).min(2);
+        let mut payload = Vec::with_capacity(name.len() + 1);
+        payload.push(IS);
+        payload.extend_from_slice(name.as_bytes());
+        Some(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEND_REQUEST: [u8; 1] = [SEND];
+
+    fn responses(charset: Charset, color: bool) -> Vec<String> {
+        let mut terminal_type = TerminalType::new();
+        (0..4).map(|_| {
+            let payload = terminal_type.respond(&SEND_REQUEST, "webmud-ng", charset, color).unwrap();
+            assert_eq!(payload[0], IS);
+            String::from_utf8(payload[1..].to_vec()).unwrap()
+        }).collect()
+    }
+
+    #[test]
+    fn cycles_through_names() {
+        assert_eq!(responses(Charset::Utf8, true), ["WEBMUD-NG", "XTERM", "MTTS 397", "MTTS 397"]);
+    }
+
+    #[test]
+    fn advertises_utf_8_only_when_in_use() {
+        let latin1 = Charset::from_label("latin1").unwrap();
+        assert_eq!(responses(latin1, true)[2], "MTTS 393");
+        assert_eq!(responses(Charset::Cp437, true)[2], "MTTS 393");
+    }
+
+    #[test]
+    fn leaves_out_colors_when_color_is_off() {
+        assert_eq!(responses(Charset::Utf8, false), ["WEBMUD-NG", "DUMB", "MTTS 132", "MTTS 132"]);
+        assert_eq!(responses(Charset::Cp437, false)[2], "MTTS 128");
+    }
+}
@@ -1,3 +1,6 @@
+// Sidebar cells for exits whose names differ from their element ids //
+var exit_ids = {"in": "m_i", "out": "m_o"};
+
 // Process any ATCP messages //
 function handle_ATCP(data)
 {
@@ -23,12 +26,16 @@ function handle_ATCP(data)
 	
 	if (data.ATCP_Room_Exits)
 	{	
+		show_sidebar();
+		
 		m_nw.innerHTML = m_n.innerHTML = m_ne.innerHTML = m_e.innerHTML = m_se.innerHTML = m_s.innerHTML = m_sw.innerHTML 
 			= m_w.innerHTML = m_in.innerHTML = m_out.innerHTML = m_up.innerHTML = m_down.innerHTML = "";
 		
 		for (var i in data.ATCP_Room_Exits)
 		{					
-			document.getElementById("m_" + data.ATCP_Room_Exits[i]).innerHTML = data.ATCP_Room_Exits[i].toUpperCase();
+			var exit = document.getElementById(exit_ids[data.ATCP_Room_Exits[i]] || ("m_" + data.ATCP_Room_Exits[i]));
+			if (exit)
+				exit.innerHTML = data.ATCP_Room_Exits[i].toUpperCase();
 		}
 	}
 	
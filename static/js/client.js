@@ -22,6 +22,18 @@ var m_nw, m_n, m_ne, m_e, m_se, m_s, m_sw, m_w, m_up, m_down, m_in, m_out;
 
 var map;
 
+// Cleared while the MUD has taken over echoing (e.g. for password entry) //
+var local_echo = true;
+
+// Last window size (in characters) reported to the MUD via NAWS, once connected //
+var naws_cols = 0, naws_rows = 0, naws_active = false;
+
+// Newest version of the JSON protocol this client speaks, and the one the server agreed to //
+//	- browsers without JSON.stringify fall back to the older PHUD: text protocol
+var PROTOCOL_VERSION = 1;
+var protocol_version = 0;
+var json_protocol = !!(window.JSON && JSON.stringify);
+
 var WEBSOCKET_KEY_COUNT = 0;
 if (WebSocket) {
 	if (WebSocket.prototype) {
@@ -95,6 +107,8 @@ $(document).ready(function(){
 	nl_raw = document.getElementById("nl_raw");
 
 	objDiv = document.getElementById("output");
+	objDiv.onclick = handle_send_click;
+	document.getElementById("prompt").onclick = handle_send_click;
 	
 	num_msgs = 0;	
 	next_del = 0;	
@@ -139,6 +153,8 @@ $(document).ready(function(){
 	
 	socket.onopen = function() {
     	set_connected_phudbase();
+    	if (json_protocol)
+    		send_message({"type": "hello", "version": PROTOCOL_VERSION});
     }
 	
 	socket.onmessage = function(evt) {				
@@ -161,34 +177,171 @@ $(document).ready(function(){
 function sendDirect(data) 
 {
    	if (data != "")
-	{	
-   		if (mode == "websocket")	   			
-   			socket.send(data);
-   		else
-   			sendTextToFlash(data);
-		return true;
-	} else {
+		return send_raw(data);
+	else
 		return false;
-	}	
+}
+
+function send_raw(data)
+{
+	if (mode == "websocket")
+		socket.send(data);
+	else
+		sendTextToFlash(data);
+	return true;
+}
+
+// Send a message of the JSON protocol //
+//	- legacy is the same message in the PHUD: text protocol, for browsers without JSON
+function send_message(msg, legacy)
+{
+	if (json_protocol)
+		return send_raw(JSON.stringify(msg));
+	else if (typeof(legacy) != "undefined")
+		return send_raw(legacy);
+	return false;
+}
+
+// Send a line of input to the MUD //
+function send_input(text)
+{
+	return send_message({"type": "input", "text": text}, text);
 }
 
 function send() 
 {
 	s = document.getElementById("user_input").value;
 
-	print(s, "tnc_grey");
+	if (local_echo)
+		print(s, "tnc_grey");
 	
-	if (mode == "websocket")
-		socket.send(s);
-	else
-		sendTextToFlash(s);
+	send_input(s);
 	
 	document.getElementById("user_input").value = "";
 	return true;	
 }
 
+// Mask the input box and stop echoing locally while the MUD echoes for us //
+function set_local_echo(enabled)
+{
+	local_echo = enabled;
+	
+	var input = document.getElementById("user_input");
+	try {
+		input.type = enabled ? "text" : "password";
+	} catch (e) {
+		// Older browsers do not allow changing the type of an input //
+	}
+	input.setAttribute("autocomplete", enabled ? "on" : "off");
+}
+
+function send_move(dir)
+{
+	print(dir, "tnc_grey");
+	send_input(dir);
+}
+
+// Send the command behind a clicked MXP <send> link //
+function handle_send_click(e)
+{
+	e = e || window.event;
+	var target = e.target || e.srcElement;
+	
+	while (target && target != this)
+	{
+		if (target.getAttribute && target.getAttribute("data-send") != null)
+		{
+			var command = target.getAttribute("data-send");
+			if (local_echo)
+				print(command, "tnc_grey");
+			send_input(command);
+			return false;
+		}
+		target = target.parentNode;
+	}
+	return true;
+}
+
+function show_sidebar()
+{
+	$("#c_right").show();
+	$("#c_output").css("margin-right", "");
+	$("#c_footer").css("margin-right", "");
+	send_window_size();
+}
+
+// Connect to the MUD given in the login form //
+//	- the host, port and TLS setting are left out when the server has a fixed target
+//	- in the text protocol this is PHUD:CONNECT <host> <port> <tls> [option=value ...]
+function send_connect()
+{
+	var msg = {"type": "connect"};
+	var cmd = "PHUD:CONNECT";
+	if (!WNG_TARGET)
+	{
+		msg.host = document.getElementById("mhost").value;
+		msg.port = parseInt(document.getElementById("mport").value, 10);
+		msg.tls = document.getElementById("mtls").checked;
+		cmd += " " + msg.host + " " + document.getElementById("mport").value + " " + msg.tls;
+	}
+	
+	var charset = document.getElementById("mcharset").value;
+	if (charset != "")
+	{
+		msg.charset = charset;
+		cmd += " charset=" + charset;
+	}
+	
+	var color = document.getElementById("mcolor").value;
+	if (color != "")
+	{
+		msg.color = (color == "true");
+		cmd += " color=" + color;
+	}
+	
+	return send_message(msg, cmd);
+}
+
+// Measure the output pane in characters of its monospace font //
+function output_size()
+{
+	var probe = document.createElement("span");
+	probe.style.visibility = "hidden";
+	probe.style.position = "absolute";
+	probe.style.whiteSpace = "pre";
+	probe.innerHTML = "MMMMMMMMMM";
+	objDiv.appendChild(probe);
+	var char_width = probe.offsetWidth / 10;
+	var char_height = probe.offsetHeight;
+	objDiv.removeChild(probe);
+	
+	if (!char_width || !char_height) return null;
+	
+	// clientWidth includes the 5px padding on either side //
+	return [
+		Math.max(1, Math.floor((objDiv.clientWidth - 10) / char_width)),
+		Math.max(1, Math.floor((objDiv.clientHeight - 10) / char_height))
+	];
+}
+
+// Tell the server about the current window size if it has changed //
+function send_window_size()
+{
+	if (!naws_active) return;
+	
+	var size = output_size();
+	if (!size || (size[0] == naws_cols && size[1] == naws_rows)) return;
+	
+	naws_cols = size[0];
+	naws_rows = size[1];
+	send_message({"type": "naws", "cols": naws_cols, "rows": naws_rows}, "PHUD:NAWS " + naws_cols + " " + naws_rows);
+}
+
 function postLogin() 
 {
+	naws_active = true;
+	send_window_size();
+	
 	document.getElementById("user_input").value = "";
 	$("#data_form").fadeIn(500, function() {document.getElementById("user_input").focus()});	
 	$("#login_area").remove();	
@@ -214,6 +367,7 @@ function set_disconnected_mud()
 
 function set_disconnected()
 {
+	set_local_echo(true);
 	conn_div.style.background = "red";
     conn_div.innerHTML = "<p style='font-size: 1.25em; font-weight: bold; color: #fff;'>DISCONNECTED</p>";
     m_conn_div.style.background = "red";
@@ -234,16 +388,34 @@ function handle_read(s)
 	//console.log(s);
 	//data = eval("(" + s + ")");
 	data = do_json_parse(s);
+	
+	// Messages of the JSON protocol are tagged with their type //
+	if (data.type)
+	{
+		handle_message(data);
+		return;
+	}
 
-	// Check for ATCP messages ( //
-	// Unimplemented.
-	//handle_ATCP(data);
+	// Check for ATCP messages //
+	handle_ATCP(data);
+	
+	// Hand any GMCP messages to the GMCP hooks //
+	if (data.gmcp) handle_GMCP(data.gmcp["package"], parse_gmcp_data(data.gmcp.data));
+	
+	// Hand any MSDP variables to the MSDP hooks //
+	if (data.msdp) handle_MSDP(data.msdp);
+	
+	// The MUD cleared the screen //
+	if (data.clear) ow_Clear();
 	
 	// Output a standard message //
 	if (data.message) ow_Write(data.message);
 	
-	// Write a WebMud server status message //
-	if (data.server_status) ss_Write(data.server_status);
+	// The MUD has switched echoing on or off //
+	if (data.local_echo) set_local_echo(data.local_echo == "on");
+	
+	// Pin the latest prompt (ended by telnet GA/EOR) in the status line //
+	if (data.prompt) prompt_Write(data.prompt);
 	
 	// Set the connection status for the MUD
 	if (data.conn_status) 
@@ -264,18 +436,105 @@ function handle_read(s)
 	}
 }
 
-function ow_Write(text)
-{	
-	//var objDiv = window.top.document.getElementById("output");
+// Handle a message of the JSON protocol //
+function handle_message(data)
+{
+	switch (data.type)
+	{
+		case "hello":
+			protocol_version = data.version;
+			break;
+		
+		case "output":
+			if (data.stream == "prompt")
+				prompt_Write(data.data);
+			else
+				ow_Write(data.data);
+			break;
+		
+		case "clear":
+			ow_Clear();
+			break;
+		
+		case "status":
+			if (data.status == "connecting")
+				ow_Write("<br>Attempting to establish a " + (data.tls ? "TLS " : "") + "connection with " + escape_html(data.host + ":" + data.port) + "<br>");
+			else if (data.status == "connected")
+				set_connected_mud();
+			else if (data.status == "disconnected")
+			{
+				set_disconnected_mud();
+				handle_ATCP({"ATCP_Disconnect": "true"});
+			}
+			break;
+		
+		case "error":
+			ow_Write("<br>" + escape_html(data.message) + "<br>");
+			break;
+		
+		// The MUD has turned a telnet option on or off //
+		case "option":
+			if (data.option == "echo")
+				set_local_echo(!data.enabled);
+			break;
+		
+		case "gmcp":
+			handle_GMCP(data["package"], parse_gmcp_data(data.data));
+			break;
+		
+		case "msdp":
+			handle_MSDP(data.data);
+			break;
+		
+		// Hand ATCP to the hooks as the text protocol would, e.g. {"ATCP_Room_Exits": [...]} //
+		case "atcp":
+			var atcp = {};
+			atcp["ATCP_" + data["package"].replace(/\./g, "_")] = data.data;
+			handle_ATCP(atcp);
+			break;
+	}
+}
 
+// GMCP data arrives as a JSON string, which may be empty or malformed //
+function parse_gmcp_data(data)
+{
+	if (data == "") return null;
+	try {
+		return do_json_parse(data);
+	} catch (e) {
+		return null;
+	}
+}
+
+function escape_html(text)
+{
+	return String(text).replace(/&/g, "&amp;").replace(/</g, "&lt;").replace(/>/g, "&gt;").replace(/"/g, "&quot;");
+}
+
+function sanitize(text)
+{
 	if (filterXSS) {
 		text = filterXSS(text, {
 			"whiteList": {
-				"span": ["class"],
-				"br": []
+				"span": ["class", "style"],
+				"br": [],
+				"a": ["href", "target", "rel", "class", "data-send", "title"]
 			}
 		});
 	}
+	return text;
+}
+
+function prompt_Write(text)
+{
+	document.getElementById("prompt").innerHTML = sanitize(text);
+}
+
+function ow_Write(text)
+{	
+	//var objDiv = window.top.document.getElementById("output");
+
+	text = sanitize(text);
 
 	text = '<span id="msg' + num_msgs + '">' + text + '</span>'; 
 
@@ -294,6 +553,13 @@ function ow_Write(text)
 	objDiv.scrollTop = objDiv.scrollHeight;
 }
 
+function ow_Clear()
+{
+	objDiv.innerHTML = "";
+	num_msgs = 0;
+	next_del = 0;
+}
+
 function trim_ow()
 {
 	var elem;	
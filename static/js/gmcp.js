@@ -0,0 +1,32 @@
+// Process any GMCP messages //
+//	- pkg is the package and message name, e.g. "Char.Vitals"
+//	- data is the parsed JSON payload, or null if the server sent none
+function handle_GMCP(pkg, data)
+{
+	if (pkg == "Char.Vitals")
+	{
+	
+	}
+	
+	if (pkg == "Room.Info")
+	{
+	
+	}
+	
+	if (pkg == "Char.Items.List")
+	{
+	
+	}
+}
+
+// Send a GMCP message to the MUD //
+//	- ex. sendGMCP("Core.Supports.Set", ["Char 1", "Room 1"])
+function sendGMCP(pkg, data)
+{
+	if (typeof(data) == "undefined")
+		return send_message({"type": "gmcp", "package": pkg}, "PHUD:GMCP " + pkg);
+	
+	// The data goes as a JSON string, the way the MUD will receive it //
+	var json = JSON.stringify(data);
+	return send_message({"type": "gmcp", "package": pkg, "data": json}, "PHUD:GMCP " + pkg + " " + json);
+}
@@ -0,0 +1,46 @@
+// Process any MSDP messages //
+//	- data is an object of the variables the server sent, ex. {"HEALTH": "100", "ROOM": {"VNUM": "6008", ...}}
+//	- tables become objects and arrays become arrays; every leaf value is a string
+function handle_MSDP(data)
+{
+	// Drive the ATCP hooks with the equivalent MSDP variables //
+	var atcp = {};
+	
+	if (data.CHARACTER_NAME)
+		atcp.ATCP_Char_Name = data.CHARACTER_NAME;
+	
+	if (data.HEALTH || data.MANA || data.MOVEMENT)
+	{
+		atcp.ATCP_Char_Vitals = "H:" + (data.HEALTH || 0) + "/" + (data.HEALTH_MAX || 0)
+			+ " M:" + (data.MANA || 0) + "/" + (data.MANA_MAX || 0)
+			+ " E:" + (data.MOVEMENT || 0) + "/" + (data.MOVEMENT_MAX || 0);
+	}
+	
+	if (data.ROOM_VNUM)
+		atcp.ATCP_Room_Num = data.ROOM_VNUM;
+	
+	var room = data.ROOM;
+	if (room && typeof(room) == "object")
+	{
+		if (room.VNUM)
+			atcp.ATCP_Room_Num = room.VNUM;
+		
+		if (room.EXITS && typeof(room.EXITS) == "object")
+		{
+			atcp.ATCP_Room_Exits = [];
+			for (var dir in room.EXITS)
+				atcp.ATCP_Room_Exits.push(dir);
+		}
+	}
+	
+	handle_ATCP(atcp);
+}
+
+// Send an MSDP command to the MUD //
+//	- command is one of LIST, REPORT, RESET, SEND, UNREPORT
+//	- ex. sendMSDP("REPORT", ["HEALTH", "HEALTH_MAX", "ROOM"])
+function sendMSDP(command, args)
+{
+	return send_message({"type": "msdp", "command": command, "args": args},
+		"PHUD:MSDP " + JSON.stringify({"command": command, "args": args}));
+}